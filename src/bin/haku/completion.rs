@@ -0,0 +1,329 @@
+use std::collections::HashSet;
+
+use crate::config::{CliOpt, OptValue, CLI_OPTS, COMPLETE_RECIPES_OPT};
+use haku::vm::Engine;
+
+/// Returns enabled recipes for shell completion scripts: one line per recipe, the recipe name
+/// followed by names of its arguments, all separated with spaces. System recipes and recipes
+/// hidden by name collisions are skipped
+fn recipe_lines(eng: &Engine) -> Vec<String> {
+    let mut names = HashSet::new();
+    let mut lines = Vec::new();
+    for r in eng.recipes() {
+        if r.system || !names.insert(r.name.clone()) {
+            continue;
+        }
+        let mut line = r.name.clone();
+        for v in r.vars.iter() {
+            line.push(' ');
+            line += &v.name;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Prints enabled recipes for shell completion scripts (see `recipe_lines`)
+pub fn print_recipes(eng: &Engine) {
+    for line in recipe_lines(eng) {
+        println!("{}", line);
+    }
+}
+
+/// Returns all names of an option: `-f` and `--file`
+fn opt_names(o: &CliOpt) -> Vec<String> {
+    let mut names = Vec::new();
+    if !o.short.is_empty() {
+        names.push(format!("-{}", o.short));
+    }
+    names.push(format!("--{}", o.long));
+    names
+}
+
+/// Returns names of all options that require a value
+fn value_opts() -> Vec<String> {
+    CLI_OPTS.iter().filter(|o| !o.hint.is_empty()).flat_map(opt_names).collect()
+}
+
+fn bash_script() -> String {
+    let all: Vec<String> = CLI_OPTS.iter().flat_map(opt_names).collect();
+    let mut cases = String::new();
+    for o in CLI_OPTS.iter() {
+        let action = match o.value {
+            OptValue::Flag | OptValue::MultiFlag => continue,
+            OptValue::Text | OptValue::Assign => "return".to_string(),
+            OptValue::File | OptValue::Files => "COMPREPLY=($(compgen -f -- \"$cur\")); return".to_string(),
+            OptValue::Recipe => {
+                "COMPREPLY=($(compgen -W \"$(_haku_recipes | cut -d' ' -f1)\" -- \"$cur\")); return".to_string()
+            }
+            OptValue::Choice(vals) => format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return", vals.join(" ")),
+        };
+        cases += &format!("        {})\n            {}\n            ;;\n", opt_names(o).join("|"), action);
+    }
+    format!(
+        r#"# bash completion for haku
+_haku_recipes() {{
+    haku "${{_haku_file[@]}}" {complete} 2>/dev/null
+}}
+
+_haku() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    local value_opts=" {values} "
+    local recipe="" skip=0 i word
+    _haku_file=()
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${{COMP_WORDS[i]}}"
+        if [[ $skip == 1 ]]; then
+            skip=0
+            continue
+        fi
+        case "$word" in
+            -f|--file) _haku_file=(--file "${{COMP_WORDS[i+1]}}"); skip=1 ;;
+            --file=*) _haku_file=("$word") ;;
+            +) recipe="" ;;
+            -*) [[ "$value_opts" == *" $word "* ]] && skip=1 ;;
+            *) [[ -z "$recipe" ]] && recipe="$word" ;;
+        esac
+    done
+
+    case "$prev" in
+{cases}    esac
+
+    if [[ "$cur" == -* ]]; then
+        local named=""
+        if [[ -n "$recipe" ]]; then
+            named=$(_haku_recipes | awk -v r="$recipe" '$1 == r {{ for (i = 2; i <= NF; i++) printf "--%s= ", $i }}')
+        fi
+        COMPREPLY=($(compgen -W "{all} $named" -- "$cur"))
+        [[ "${{COMPREPLY[0]}}" == *= ]] && compopt -o nospace
+        return
+    fi
+    if [[ -z "$recipe" ]]; then
+        COMPREPLY=($(compgen -W "$(_haku_recipes | cut -d' ' -f1)" -- "$cur"))
+        return
+    fi
+    COMPREPLY=($(compgen -f -- "$cur"))
+}}
+
+complete -F _haku haku
+"#,
+        complete = COMPLETE_RECIPES_OPT,
+        values = value_opts().join(" "),
+        cases = cases,
+        all = all.join(" "),
+    )
+}
+
+/// Escapes characters that have special meaning inside zsh `_arguments` descriptions
+fn zsh_escape(s: &str) -> String {
+    s.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]")
+}
+
+fn zsh_script() -> String {
+    let mut specs = String::new();
+    for o in CLI_OPTS.iter() {
+        let names = opt_names(o);
+        let (suffix_short, suffix_long) = if o.hint.is_empty() { ("", "") } else { ("+", "=") };
+        let spelled: Vec<String> =
+            names
+                .iter()
+                .map(|n| {
+                    if n.starts_with("--") {
+                        format!("{}{}", n, suffix_long)
+                    } else {
+                        format!("{}{}", n, suffix_short)
+                    }
+                })
+                .collect();
+        let exclude = if o.value == OptValue::MultiFlag { "*".to_string() } else { format!("({})", names.join(" ")) };
+        let names_spec = if spelled.len() == 1 { spelled[0].clone() } else { format!("{{{}}}", spelled.join(",")) };
+        let action = match o.value {
+            OptValue::Flag | OptValue::MultiFlag => String::new(),
+            OptValue::Text | OptValue::Assign => format!(":{}: ", o.hint.to_lowercase()),
+            OptValue::File | OptValue::Files => format!(":{}:_files", o.hint.to_lowercase()),
+            OptValue::Recipe => format!(":{}:_haku_recipe_names", o.hint.to_lowercase()),
+            OptValue::Choice(vals) => format!(":{}:({})", o.hint.to_lowercase(), vals.join(" ")),
+        };
+        specs += &format!("    '{}'{}'[{}]{}' \\\n", exclude, names_spec, zsh_escape(o.desc), action);
+    }
+    format!(
+        r#"#compdef haku
+
+_haku_list() {{
+    local file=${{opt_args[-f]:-${{opt_args[--file]}}}}
+    haku ${{file:+--file "$file"}} {complete} 2>/dev/null
+}}
+
+_haku_recipe_names() {{
+    local -a recipes
+    recipes=(${{(f)"$(_haku_list)"}})
+    compadd -- ${{recipes%% *}}
+}}
+
+_haku_args() {{
+    local recipe="" word
+    for word in "${{words[@]:0:CURRENT-1}}"; do
+        case "$word" in
+            +) recipe="" ;;
+            -*) ;;
+            *) [[ -z "$recipe" ]] && recipe="$word" ;;
+        esac
+    done
+    if [[ -z "$recipe" ]]; then
+        _haku_recipe_names
+    elif [[ "$PREFIX" == -* ]]; then
+        local line
+        for line in ${{(f)"$(_haku_list)"}}; do
+            local -a parts
+            parts=(${{=line}})
+            if [[ "$parts[1]" == "$recipe" ]]; then
+                compadd -S '' -- ${{parts[2,-1]/#/--}}=
+            fi
+        done
+    else
+        _files
+    fi
+}}
+
+_haku() {{
+    _arguments -s \
+{specs}    '*:: :_haku_args'
+}}
+
+_haku "$@"
+"#,
+        complete = COMPLETE_RECIPES_OPT,
+        specs = specs,
+    )
+}
+
+/// Escapes single quotes for fish strings
+fn fish_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish_script() -> String {
+    let mut opts = String::new();
+    for o in CLI_OPTS.iter() {
+        let mut line = "complete -c haku".to_string();
+        if !o.short.is_empty() {
+            line += &format!(" -s {}", o.short);
+        }
+        line += &format!(" -l {}", o.long);
+        match o.value {
+            OptValue::Flag | OptValue::MultiFlag => {}
+            OptValue::Text | OptValue::Assign => line += " -x",
+            OptValue::File | OptValue::Files => line += " -r -F",
+            OptValue::Recipe => line += " -x -a '(__haku_recipe_names)'",
+            OptValue::Choice(vals) => line += &format!(" -x -a '{}'", vals.join(" ")),
+        }
+        line += &format!(" -d '{}'\n", fish_escape(o.desc));
+        opts += &line;
+    }
+    format!(
+        r#"# fish completion for haku
+function __haku_list
+    set -l args
+    set -l tokens (commandline -opc)
+    for i in (seq (count $tokens))
+        if contains -- $tokens[$i] -f --file; and test $i -lt (count $tokens)
+            set args --file $tokens[(math $i + 1)]
+        end
+    end
+    haku $args {complete} 2>/dev/null
+end
+
+function __haku_recipe_names
+    __haku_list | string replace -r ' .*' ''
+end
+
+function __haku_current_recipe
+    set -l recipe
+    set -l skip 0
+    for tok in (commandline -opc)[2..-1]
+        if test $skip = 1
+            set skip 0
+        else if test "$tok" = +
+            set recipe
+        else if contains -- $tok {values}
+            set skip 1
+        else if not string match -q -- '-*' $tok; and test -z "$recipe"
+            set recipe $tok
+        end
+    end
+    echo $recipe
+end
+
+function __haku_args
+    set -l recipe (__haku_current_recipe)
+    if test -z "$recipe"
+        __haku_recipe_names
+        return
+    end
+    for line in (__haku_list)
+        set -l parts (string split ' ' -- $line)
+        if test "$parts[1]" = "$recipe"
+            for arg in $parts[2..-1]
+                echo -- "--$arg="
+            end
+        end
+    end
+end
+
+complete -c haku -f -a '(__haku_args)'
+{opts}"#,
+        complete = COMPLETE_RECIPES_OPT,
+        values = value_opts().join(" "),
+        opts = opts,
+    )
+}
+
+/// Prints a completion script for a shell. The shell name must be already validated
+pub fn print_script(shell: &str) {
+    match shell {
+        "bash" => print!("{}", bash_script()),
+        "zsh" => print!("{}", zsh_script()),
+        "fish" => print!("{}", fish_script()),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod completion_test {
+    use super::*;
+    use haku::vm::RunOpts;
+
+    /// Returns `true` if the script contains `word` that is not a part of a longer option name
+    fn has_word(script: &str, word: &str) -> bool {
+        let is_name = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '-');
+        script.match_indices(word).any(|(idx, _)| {
+            !is_name(script[..idx].chars().next_back()) && !is_name(script[idx + word.len()..].chars().next())
+        })
+    }
+
+    #[test]
+    fn recipes() {
+        let src = "_before:\nbuild target +flags:\n_default: build\ntest:\nbuild:\n#[feature(never)]\nhidden:\n";
+        let mut eng = Engine::new(RunOpts::new());
+        assert!(eng.load_from_str(src).is_ok());
+        assert_eq!(recipe_lines(&eng), vec!["build target flags".to_string(), "test".to_string()]);
+    }
+
+    #[test]
+    fn scripts_list_all_options() {
+        for (shell, script) in [("bash", bash_script()), ("zsh", zsh_script()), ("fish", fish_script())] {
+            for o in CLI_OPTS.iter() {
+                let (long, short) = if shell == "fish" {
+                    (format!("-l {}", o.long), format!("-s {}", o.short))
+                } else {
+                    (format!("--{}", o.long), format!("-{}", o.short))
+                };
+                assert!(has_word(&script, &long), "{}: {}", shell, long);
+                if !o.short.is_empty() {
+                    assert!(has_word(&script, &short), "{}: {}", shell, short);
+                }
+            }
+        }
+    }
+}
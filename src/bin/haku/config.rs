@@ -4,20 +4,246 @@ use std::iter::FromIterator;
 use std::process::exit;
 
 use haku::errors::HakuError;
+use haku::vm::{OutputMode, RecipeCall};
+
+/// Separates recipes in command line: `haku clean + build v1.0 + test`
+const RECIPE_SEP: &str = "+";
+/// A hidden option: prints enabled recipes and their arguments for shell completion scripts
+pub const COMPLETE_RECIPES_OPT: &str = "--complete-recipes";
+
+/// What a command-line option value can be, used by shell completion scripts
+#[derive(Clone, Copy, PartialEq)]
+pub enum OptValue {
+    /// the option is a flag without value
+    Flag,
+    /// the option is a flag that can be repeated
+    MultiFlag,
+    /// any text
+    Text,
+    /// a path to a file
+    File,
+    /// a path to a file, the option can be used a few times
+    Files,
+    /// a variable assignment `name=value`, the option can be used a few times
+    Assign,
+    /// a recipe name
+    Recipe,
+    /// one of the listed words
+    Choice(&'static [&'static str]),
+}
+
+/// Command-line option description
+pub struct CliOpt {
+    pub short: &'static str,
+    pub long: &'static str,
+    pub desc: &'static str,
+    /// value placeholder in help, empty for flags
+    pub hint: &'static str,
+    pub value: OptValue,
+}
+
+/// All command-line options, except hidden ones
+pub const CLI_OPTS: [CliOpt; 23] = [
+    CliOpt { short: "h", long: "help", desc: "Show this help", hint: "", value: OptValue::Flag },
+    CliOpt { short: "v", long: "verbose", desc: "Display extra information", hint: "", value: OptValue::MultiFlag },
+    CliOpt { short: "", long: "version", desc: "Display application version", hint: "", value: OptValue::Flag },
+    CliOpt {
+        short: "",
+        long: "dry-run",
+        desc: "Dry run: do not external command, only display them",
+        hint: "",
+        value: OptValue::Flag,
+    },
+    CliOpt { short: "l", long: "list", desc: "list available commands", hint: "", value: OptValue::Flag },
+    CliOpt { short: "f", long: "file", desc: "Haku file path", hint: "FILENAME", value: OptValue::File },
+    CliOpt {
+        short: "",
+        long: "env-file",
+        desc: "load environment variables from a dotenv file",
+        hint: "FILENAME",
+        value: OptValue::Files,
+    },
+    CliOpt {
+        short: "",
+        long: "set",
+        desc: "set script variable before the script starts",
+        hint: "NAME=VALUE",
+        value: OptValue::Assign,
+    },
+    CliOpt {
+        short: "",
+        long: "list-vars",
+        desc: "list script global variables and their values after script headers are executed",
+        hint: "",
+        value: OptValue::Flag,
+    },
+    CliOpt { short: "", long: "feature", desc: "use features", hint: "Feature1,Feature2", value: OptValue::Text },
+    CliOpt {
+        short: "a",
+        long: "all",
+        desc: "list all recipes: available and disabled ones",
+        hint: "",
+        value: OptValue::Flag,
+    },
+    CliOpt {
+        short: "",
+        long: "list-features",
+        desc: "list user-defined features used by a script",
+        hint: "",
+        value: OptValue::Flag,
+    },
+    CliOpt { short: "", long: "show", desc: "show recipe content", hint: "RECIPE_NAME", value: OptValue::Recipe },
+    CliOpt { short: "", long: "time", desc: "show time taken by a recipe", hint: "", value: OptValue::Flag },
+    CliOpt {
+        short: "",
+        long: "recipes",
+        desc: "run a few recipes one by one",
+        hint: "RECIPE1,RECIPE2",
+        value: OptValue::Recipe,
+    },
+    CliOpt {
+        short: "",
+        long: "no-cache",
+        desc: "always run recipes marked with cache attribute",
+        hint: "",
+        value: OptValue::Flag,
+    },
+    CliOpt {
+        short: "",
+        long: "clear-cache",
+        desc: "remove all cached recipe results",
+        hint: "",
+        value: OptValue::Flag,
+    },
+    CliOpt {
+        short: "",
+        long: "exit-code",
+        desc: "exit code on failure: child(default), kind, or one",
+        hint: "MODE",
+        value: OptValue::Choice(&["child", "kind", "one"]),
+    },
+    CliOpt {
+        short: "",
+        long: "output",
+        desc: "shell command output: plain(default), prefix, or time",
+        hint: "MODE",
+        value: OptValue::Choice(&["plain", "prefix", "time"]),
+    },
+    CliOpt {
+        short: "",
+        long: "format",
+        desc: "recipe list format: text(default) or json; graph format: text(default) or dot",
+        hint: "FORMAT",
+        value: OptValue::Choice(&["text", "json", "dot"]),
+    },
+    CliOpt {
+        short: "",
+        long: "graph",
+        desc: "show dependency tree of given recipes or of all recipes",
+        hint: "",
+        value: OptValue::Flag,
+    },
+    CliOpt {
+        short: "j",
+        long: "jobs",
+        desc: "run up to N independent recipes at the same time (0 - number of CPUs)",
+        hint: "N",
+        value: OptValue::Text,
+    },
+    CliOpt {
+        short: "",
+        long: "completions",
+        desc: "print shell completion script: bash, zsh, or fish",
+        hint: "SHELL",
+        value: OptValue::Choice(&["bash", "zsh", "fish"]),
+    },
+];
+
+/// Returns `true` if a long option requires a value: an argument `--NAME=VALUE` with any
+/// other NAME is a named recipe argument
+fn opt_has_value(name: &str) -> bool {
+    CLI_OPTS.iter().any(|o| o.long == name && !o.hint.is_empty())
+}
+
+/// Extracts named recipe arguments (`--name=value`) from the command line, so getopts does not
+/// reject them as unknown options. Returns the rest of arguments and a list of named ones,
+/// each with the index of the recipe group (groups are separated with `+`) it belongs to
+fn split_named_args(args: &[String]) -> (Vec<String>, Vec<(usize, String)>) {
+    let mut rest = Vec::new();
+    let mut named = Vec::new();
+    let mut group = 0usize;
+    for arg in args.iter() {
+        if arg == RECIPE_SEP {
+            group += 1;
+        } else if let Some((name, _)) = arg.strip_prefix("--").and_then(|s| s.split_once('=')) {
+            if !name.is_empty() && !opt_has_value(name) {
+                named.push((group, arg.clone()));
+                continue;
+            }
+        }
+        rest.push(arg.clone());
+    }
+    (rest, named)
+}
+
+/// Splits a variable assignment `name=value`. Returns `None` if the argument is not an
+/// assignment or the name is not a valid identifier
+fn split_assign(arg: &str) -> Option<(String, String)> {
+    let (name, value) = arg.split_once('=')?;
+    let mut chars = name.chars();
+    let first = chars.next()?;
+    if !(first.is_alphabetic() || first == '_') || !chars.all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    Some((name.to_string(), value.to_string()))
+}
+
+/// How haku chooses its exit code when a script fails
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExitMode {
+    /// exit code of the failed external command, or 1 for all other errors
+    Child,
+    /// number of the error kind (see `HakuError::kind`)
+    Kind,
+    /// always 1
+    One,
+}
+
+/// How haku prints the list of recipes or the dependency graph
+#[derive(Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    /// human-readable list
+    Text,
+    /// JSON document for editor plugins and shell completion
+    Json,
+    /// Graphviz DOT graph (only for dependency graph)
+    Dot,
+}
 
 pub struct Config {
     pub dry_run: bool,
     pub list: bool,
     pub verbose: usize,
     pub version: bool,
-    pub args: Vec<String>,
     pub filename: String,
-    pub recipe: String,
+    pub recipes: Vec<RecipeCall>,
     pub features: Vec<String>,
     pub show_all: bool,
     pub show_features: bool,
     pub show_recipe: String,
     pub show_time: bool,
+    pub jobs: usize,
+    pub no_cache: bool,
+    pub clear_cache: bool,
+    pub exit_mode: ExitMode,
+    pub output: OutputMode,
+    pub list_format: ListFormat,
+    pub completions: String,
+    pub env_files: Vec<String>,
+    pub vars: Vec<(String, String)>,
+    pub list_vars: bool,
+    pub graph: bool,
+    pub complete_recipes: bool,
 }
 
 impl Config {
@@ -29,18 +255,29 @@ impl Config {
             version: false,
             show_all: false,
             show_features: false,
-            args: Vec::new(),
             filename: String::new(),
-            recipe: String::new(),
+            recipes: Vec::new(),
             features: Vec::new(),
             show_recipe: String::new(),
             show_time: false,
+            jobs: 1,
+            no_cache: false,
+            clear_cache: false,
+            exit_mode: ExitMode::Child,
+            output: OutputMode::Plain,
+            list_format: ListFormat::Text,
+            completions: String::new(),
+            env_files: Vec::new(),
+            vars: Vec::new(),
+            list_vars: false,
+            graph: false,
+            complete_recipes: false,
         }
     }
 }
 
 fn print_usage(program: &str, opts: &Options) {
-    let brief = format!("Usage: {} [options] recipe [arguments]", program);
+    let brief = format!("Usage: {} [options] recipe [arguments] [+ recipe [arguments]]...", program);
     print!("{}", opts.usage(&brief));
 }
 
@@ -50,19 +287,21 @@ pub fn parse_args() -> Result<Config, HakuError> {
     let mut conf = Config::new();
 
     let mut opts = Options::new();
-    opts.optflag("h", "help", "Show this help");
-    opts.optflagmulti("v", "verbose", "Display extra information");
-    opts.optflag("", "version", "Display application version");
-    opts.optflag("", "dry-run", "Dry run: do not external command, only display them");
-    opts.optflag("l", "list", "list available commands");
-    opts.optopt("f", "file", "Haku file path", "FILENAME");
-    opts.optopt("", "feature", "use features", "Feature1,Feature2");
-    opts.optflag("a", "all", "list all recipes: available and disabled ones");
-    opts.optflag("", "list-features", "list user-defined features used by a script");
-    opts.optopt("", "show", "show recipe content", "RECIPE_NAME");
-    opts.optflag("", "time", "show time taken by a recipe");
-
-    let matches: Matches = match opts.parse(&args[1..]) {
+    for o in CLI_OPTS.iter() {
+        match o.value {
+            OptValue::Flag => opts.optflag(o.short, o.long, o.desc),
+            OptValue::MultiFlag => opts.optflagmulti(o.short, o.long, o.desc),
+            OptValue::Files | OptValue::Assign => opts.optmulti(o.short, o.long, o.desc, o.hint),
+            _ => opts.optopt(o.short, o.long, o.desc, o.hint),
+        };
+    }
+
+    let (mut args_rest, named) = split_named_args(&args[1..]);
+    if let Some(pos) = args_rest.iter().position(|a| a == COMPLETE_RECIPES_OPT) {
+        args_rest.remove(pos);
+        conf.complete_recipes = true;
+    }
+    let matches: Matches = match opts.parse(&args_rest) {
         Ok(m) => m,
         Err(e) => {
             eprintln!("{}", e);
@@ -79,17 +318,55 @@ pub fn parse_args() -> Result<Config, HakuError> {
     conf.list = matches.opt_present("l");
     conf.dry_run = matches.opt_present("dry-run");
     conf.show_all = matches.opt_present("a");
+    conf.graph = matches.opt_present("graph");
+    conf.list_vars = matches.opt_present("list-vars");
     conf.show_features = matches.opt_present("list-features");
     if matches.opt_present("v") {
         conf.verbose = matches.opt_count("v");
     }
     conf.version = matches.opt_present("version");
     conf.show_time = matches.opt_present("time");
-    if !matches.free.is_empty() {
-        conf.recipe = matches.free[0].clone();
+    conf.no_cache = matches.opt_present("no-cache");
+    conf.clear_cache = matches.opt_present("clear-cache");
+    if let Some(s) = matches.opt_str("recipes") {
+        for name in s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            conf.recipes.push(RecipeCall { name: name.to_string(), args: Vec::new() });
+        }
+    }
+    for arg in matches.opt_strs("set").iter() {
+        match split_assign(arg) {
+            Some(var) => conf.vars.push(var),
+            None => {
+                eprintln!("Invalid variable assignment: {}", arg);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
     }
-    if matches.free.len() > 1 {
-        conf.args = Vec::from_iter(matches.free[1..].iter().cloned());
+    // `name=value` free arguments are script variables as well
+    let mut free = Vec::new();
+    for arg in matches.free.iter() {
+        match split_assign(arg) {
+            Some(var) => conf.vars.push(var),
+            None => free.push(arg.clone()),
+        }
+    }
+    let mut groups: Vec<Vec<String>> = free.split(|s| s == RECIPE_SEP).map(|g| g.to_vec()).collect();
+    for (idx, arg) in named.into_iter() {
+        match groups.get_mut(idx) {
+            Some(group) if !group.is_empty() => group.push(arg),
+            _ => {
+                eprintln!("Named argument {} is used without a recipe", arg);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        }
+    }
+    for group in groups.iter() {
+        if group.is_empty() {
+            continue;
+        }
+        conf.recipes.push(RecipeCall { name: group[0].clone(), args: Vec::from_iter(group[1..].iter().cloned()) });
     }
     if let Some(s) = matches.opt_str("f") {
         conf.filename = s;
@@ -97,9 +374,82 @@ pub fn parse_args() -> Result<Config, HakuError> {
     if let Some(s) = matches.opt_str("feature") {
         conf.features = s.split(',').map(|s| s.to_string()).collect();
     }
+    conf.env_files = matches.opt_strs("env-file");
     if let Some(s) = matches.opt_str("show") {
         conf.show_recipe = s;
     }
+    if let Some(s) = matches.opt_str("j") {
+        conf.jobs = match s.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("Invalid number of jobs: {}", s);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        };
+    }
+    if let Some(s) = matches.opt_str("exit-code") {
+        conf.exit_mode = match s.to_lowercase().as_str() {
+            "child" => ExitMode::Child,
+            "kind" => ExitMode::Kind,
+            "one" | "1" => ExitMode::One,
+            _ => {
+                eprintln!("Invalid exit code mode: {}", s);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        };
+    }
+    if let Some(s) = matches.opt_str("output") {
+        conf.output = match OutputMode::from_name(&s) {
+            Some(mode) => mode,
+            None => {
+                eprintln!("Invalid output mode: {}", s);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        };
+    }
+
+    if let Some(s) = matches.opt_str("completions") {
+        if !matches!(s.as_str(), "bash" | "zsh" | "fish") {
+            eprintln!("Unsupported shell: {}", s);
+            print_usage(&program, &opts);
+            exit(1);
+        }
+        conf.completions = s;
+    }
+    if let Some(s) = matches.opt_str("format") {
+        conf.list_format = match s.to_lowercase().as_str() {
+            "text" => ListFormat::Text,
+            "json" => ListFormat::Json,
+            "dot" => ListFormat::Dot,
+            _ => {
+                eprintln!("Invalid list format: {}", s);
+                print_usage(&program, &opts);
+                exit(1);
+            }
+        };
+    }
+    if matches.opt_present("format") && !conf.list && !conf.graph {
+        eprintln!("Option --format can be used only with --list or --graph");
+        print_usage(&program, &opts);
+        exit(1);
+    }
+    let format_ok = match conf.list_format {
+        ListFormat::Text => true,
+        ListFormat::Json => !conf.graph,
+        ListFormat::Dot => conf.graph,
+    };
+    if !format_ok {
+        eprintln!(
+            "Format {} cannot be used {}",
+            matches.opt_str("format").unwrap_or_default(),
+            if conf.graph { "with --graph" } else { "without --graph" }
+        );
+        print_usage(&program, &opts);
+        exit(1);
+    }
 
     Ok(conf)
 }
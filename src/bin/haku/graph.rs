@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+
+use haku::vm::{DepKind, DepTree};
+
+/// Returns a short note about a recipe that is not a regular one
+fn kind_note(kind: &DepKind) -> String {
+    match kind {
+        DepKind::Recipe => String::new(),
+        DepKind::Seen => " (*)".to_string(),
+        DepKind::Cycle => " (cycle)".to_string(),
+        DepKind::Disabled(feat) if feat.is_empty() => " (disabled)".to_string(),
+        DepKind::Disabled(feat) => format!(" (disabled: {})", feat),
+        DepKind::Missing => " (not found)".to_string(),
+    }
+}
+
+fn print_node(node: &DepTree, prefix: &str, is_last: bool, is_root: bool) {
+    let file = if node.file.is_empty() { String::new() } else { format!(" [{}]", node.file) };
+    if is_root {
+        println!("{}{}{}", node.name, file, kind_note(&node.kind));
+    } else {
+        let branch = if is_last { "└── " } else { "├── " };
+        println!("{}{}{}{}{}", prefix, branch, node.name, file, kind_note(&node.kind));
+    }
+    let child_prefix = if is_root {
+        String::new()
+    } else if is_last {
+        format!("{}    ", prefix)
+    } else {
+        format!("{}│   ", prefix)
+    };
+    for (idx, dep) in node.depends.iter().enumerate() {
+        print_node(dep, &child_prefix, idx + 1 == node.depends.len(), false);
+    }
+}
+
+/// Prints dependency trees as text. A recipe which dependencies are already displayed is
+/// marked with `(*)`
+pub fn print_tree(trees: &[DepTree]) {
+    for tree in trees.iter() {
+        print_node(tree, "", true, true);
+    }
+}
+
+/// Escapes a string to use inside a quoted DOT identifier or label
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", dot_escape(s))
+}
+
+/// Collects unique nodes, grouped by script, and edges of the trees
+fn collect<'a>(
+    node: &'a DepTree,
+    nodes: &mut Vec<&'a DepTree>,
+    names: &mut HashSet<&'a str>,
+    edges: &mut Vec<(&'a str, &'a str, bool)>,
+) {
+    // `Seen` and `Cycle` nodes point to a recipe that is described by another node
+    if !matches!(node.kind, DepKind::Seen | DepKind::Cycle) && names.insert(&node.name) {
+        nodes.push(node);
+    }
+    for dep in node.depends.iter() {
+        edges.push((&node.name, &dep.name, dep.kind == DepKind::Cycle));
+        collect(dep, nodes, names, edges);
+    }
+}
+
+/// Prints dependency trees as a Graphviz DOT graph. Recipes from the same script are
+/// grouped in a cluster, disabled and missing recipes are drawn with dashed lines, and
+/// the edges that close a cycle are red
+pub fn print_dot(trees: &[DepTree]) {
+    let mut nodes = Vec::new();
+    let mut names = HashSet::new();
+    let mut edges = Vec::new();
+    for tree in trees.iter() {
+        collect(tree, &mut nodes, &mut names, &mut edges);
+    }
+
+    println!("digraph haku {{");
+    println!("    node [shape=box];");
+    let mut files: Vec<&str> = Vec::new();
+    for n in nodes.iter() {
+        if !files.contains(&n.file.as_str()) {
+            files.push(&n.file);
+        }
+    }
+    for (idx, file) in files.iter().enumerate() {
+        let indent = if file.is_empty() { "    " } else { "        " };
+        if !file.is_empty() {
+            println!("    subgraph cluster_{} {{", idx);
+            println!("        label={};", dot_quote(file));
+        }
+        for n in nodes.iter().filter(|n| n.file == *file) {
+            let style = match n.kind {
+                DepKind::Disabled(ref feat) => {
+                    format!(", style=dashed, label=\"{}\\n{}\"", dot_escape(&n.name), dot_escape(feat))
+                }
+                DepKind::Missing => ", style=dashed, color=red".to_string(),
+                _ => String::new(),
+            };
+            println!("{}{} [tooltip={}{}];", indent, dot_quote(&n.name), dot_quote(&n.file), style);
+        }
+        if !file.is_empty() {
+            println!("    }}");
+        }
+    }
+    let mut printed = HashSet::new();
+    for (from, to, cycle) in edges.into_iter() {
+        if !printed.insert((from, to)) {
+            continue;
+        }
+        let attrs = if cycle { " [color=red, label=\"cycle\"]" } else { "" };
+        println!("    {} -> {}{};", dot_quote(from), dot_quote(to), attrs);
+    }
+    println!("}}");
+}
@@ -1,14 +1,22 @@
+mod completion;
 mod config;
+mod graph;
 use std::collections::HashSet;
+use std::fmt;
 use std::path::Path;
 use std::process::exit;
 
-use config::{parse_args, Config};
+use config::{parse_args, Config, ExitMode, ListFormat};
+use serde_json::{json, Value};
 
 use haku::errors::HakuError;
-use haku::vm::{Engine, RunOpts};
+use haku::vm::{Engine, RecipeArg, RecipeDep, RunOpts, FLAG_PASS, FLAG_QUIET};
 
-fn nice_vec_print(lst: &[String]) {
+/// Version of the JSON recipe list layout. It changes only when a field is removed or
+/// its meaning changes, so tools can check it before reading the list
+const LIST_FORMAT_VERSION: u32 = 1;
+
+fn nice_vec_print<T: fmt::Display>(lst: &[T]) {
     for (idx, s) in lst.iter().enumerate() {
         if idx > 0 {
             print!(",");
@@ -17,7 +25,88 @@ fn nice_vec_print(lst: &[String]) {
     }
 }
 
+/// Converts recipe flags to a list of their names
+fn flag_names(flags: u32) -> Vec<&'static str> {
+    let mut names = Vec::new();
+    if flags & FLAG_QUIET != 0 {
+        names.push("quiet");
+    }
+    if flags & FLAG_PASS != 0 {
+        names.push("pass");
+    }
+    names
+}
+
+/// Builds a JSON object with recipe properties. Available and disabled recipes have the
+/// same set of fields: `feature` is `null` if the recipe does not depend on features
+fn recipe_json(name: &str, desc: &str, feat: &str, flags: u32, vars: &[RecipeArg], deps: &[RecipeDep]) -> Value {
+    json!({
+        "name": name,
+        "desc": desc,
+        "args": vars.iter().map(|v| json!({
+            "name": v.name,
+            "default": v.default,
+            "list": v.list,
+        })).collect::<Vec<Value>>(),
+        "depends": deps.iter().map(|d| json!({
+            "name": d.name,
+            "call": d.call,
+        })).collect::<Vec<Value>>(),
+        "system": Engine::is_system_recipe(name),
+        "flags": flag_names(flags),
+        "feature": if feat.is_empty() { None } else { Some(feat) },
+    })
+}
+
+/// Builds a JSON document with all recipes, available and disabled ones, and user-defined
+/// features. Unlike the text list, system recipes and recipes hidden by name collisions
+/// are included, so a tool can decide what to show
+fn recipes_json(eng: &Engine) -> Value {
+    let file_name = |idx: usize| eng.file_name(idx).unwrap_or_default().to_string();
+    let recipes: Vec<Value> = eng
+        .recipes()
+        .iter()
+        .map(|r| {
+            let mut v = recipe_json(&r.name, &r.desc, &r.feat, r.flags, &r.vars, &r.depends);
+            v["file"] = json!(file_name(r.loc.file));
+            v["line"] = json!(r.loc.script_line);
+            v
+        })
+        .collect();
+    let disabled: Vec<Value> = eng
+        .disabled_recipes()
+        .iter()
+        .map(|r| {
+            let mut v = recipe_json(&r.name, &r.desc, &r.feat, r.flags, &r.vars, &r.depends);
+            v["file"] = json!(file_name(r.file));
+            v["line"] = json!(r.line);
+            v
+        })
+        .collect();
+    json!({
+        "version": LIST_FORMAT_VERSION,
+        "features": eng.user_features(),
+        "recipes": recipes,
+        "disabled": disabled,
+    })
+}
+
+/// Prints the recipe list as a JSON document (see `recipes_json`)
+fn display_recipes_json(eng: &Engine) {
+    match serde_json::to_string_pretty(&recipes_json(eng)) {
+        Ok(s) => println!("{}", s),
+        Err(e) => {
+            eprintln!("Failed to build recipe list: {}", e);
+            exit(1);
+        }
+    }
+}
+
 fn display_recipes(eng: Engine, conf: &Config) {
+    if conf.list_format == ListFormat::Json {
+        display_recipes_json(&eng);
+        return;
+    }
     if conf.show_features {
         let feats = eng.user_features();
         if !feats.is_empty() {
@@ -102,6 +191,18 @@ fn detect_taskfile() -> String {
     exit(1);
 }
 
+/// Returns the exit code for a failed script depending on the exit mode selected by a user
+fn error_exit_code(err: &HakuError, mode: ExitMode) -> i32 {
+    match mode {
+        ExitMode::One => 1,
+        ExitMode::Kind => err.kind(),
+        ExitMode::Child => match err.exit_code() {
+            Some(code) if code != 0 => code,
+            _ => 1,
+        },
+    }
+}
+
 fn main() -> Result<(), HakuError> {
     let conf = parse_args()?;
 
@@ -111,25 +212,40 @@ fn main() -> Result<(), HakuError> {
         exit(0);
     }
 
+    if !conf.completions.is_empty() {
+        completion::print_script(&conf.completions);
+        exit(0);
+    }
+
     let filename = if conf.filename.is_empty() { detect_taskfile() } else { conf.filename.clone() };
 
     let opts = RunOpts::new()
         .with_dry_run(conf.dry_run)
         .with_features(conf.features.clone())
         .with_verbosity(conf.verbose)
-        .with_time(conf.show_time);
+        .with_time(conf.show_time)
+        .with_jobs(conf.jobs)
+        .with_output(conf.output)
+        .with_env_files(conf.env_files.clone())
+        .with_vars(conf.vars.clone())
+        .with_cache(!conf.no_cache)
+        .with_signals(true);
     let mut eng = Engine::new(opts);
-    eng.set_free_args(&conf.args);
     if let Err(e) = eng.load_from_file(&filename) {
         eprintln!("{}", e);
-        exit(1);
+        exit(error_exit_code(&e, conf.exit_mode));
+    }
+
+    if conf.complete_recipes {
+        completion::print_recipes(&eng);
+        exit(0);
     }
 
     if !conf.show_recipe.is_empty() {
         match eng.recipe_content(&conf.show_recipe) {
             Err(e) => {
                 eprintln!("{}", e);
-                exit(1);
+                exit(error_exit_code(&e, conf.exit_mode));
             }
             Ok(rcp) => {
                 if !rcp.filename.is_empty() {
@@ -148,21 +264,117 @@ fn main() -> Result<(), HakuError> {
         exit(0);
     }
 
+    if conf.clear_cache {
+        if let Err(e) = eng.clear_cache() {
+            eprintln!("{}", e);
+            exit(error_exit_code(&e, conf.exit_mode));
+        }
+        if conf.recipes.is_empty() {
+            exit(0);
+        }
+    }
+
+    if conf.list_vars {
+        match eng.global_vars() {
+            Ok(vars) => {
+                for (name, value) in vars.iter() {
+                    println!("{} = {}", name, value);
+                }
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(error_exit_code(&e, conf.exit_mode));
+            }
+        }
+    }
+
+    if conf.graph {
+        let names: Vec<String> = conf.recipes.iter().map(|r| r.name.clone()).collect();
+        let trees = eng.dep_tree(&names);
+        if conf.list_format == ListFormat::Dot {
+            graph::print_dot(&trees);
+        } else {
+            graph::print_tree(&trees);
+        }
+        exit(0);
+    }
+
     if conf.list || conf.show_features {
         display_recipes(eng, &conf);
         exit(0);
     }
 
-    if let Err(e) = eng.run_recipe(&conf.recipe) {
+    if let Err(e) = eng.run_recipes(&conf.recipes) {
         match e {
             HakuError::DefaultRecipeError => {
                 println!("Default recipe is not found. Consider creating recipe '_default'");
             }
             _ => {
                 eprintln!("{}", e);
-                exit(1);
+                exit(error_exit_code(&e, conf.exit_mode));
             }
         }
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod main_test {
+    use super::*;
+
+    #[test]
+    fn json_list() {
+        let src = "_default: build\n## Build the app\n@build +flags: test(\"unit\")\ntest kind=\"all\":\n\
+                   -test:\n#[feature(zip)]\ncompress:\n#[feature(nozip)]\n@unpack dir: compress\n";
+        let mut eng = Engine::new(RunOpts::new().with_features(vec!["zip".to_string()]));
+        assert!(eng.load_from_str(src).is_ok());
+        let doc = recipes_json(&eng);
+        assert_eq!(doc["version"], json!(LIST_FORMAT_VERSION));
+        assert_eq!(doc["features"], json!(["zip", "nozip"]));
+
+        let recipes = doc["recipes"].as_array().unwrap();
+        let names: Vec<&str> = recipes.iter().map(|r| r["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["_default", "build", "compress", "test", "test"]);
+        assert_eq!(recipes[0]["system"], json!(true));
+        assert_eq!(
+            recipes[1],
+            json!({
+                "name": "build",
+                "desc": "Build the app",
+                "args": [{"name": "flags", "default": null, "list": true}],
+                "depends": [{"name": "test", "call": "test(\"unit\")"}],
+                "system": false,
+                "flags": ["quiet"],
+                "feature": null,
+                "file": "",
+                "line": 2,
+            })
+        );
+        assert_eq!(recipes[2]["feature"], json!("#[feature(zip)]"));
+        assert_eq!(recipes[3]["args"], json!([{"name": "kind", "default": "all", "list": false}]));
+        assert_eq!(recipes[4]["flags"], json!(["pass"]));
+
+        let disabled = doc["disabled"].as_array().unwrap();
+        assert_eq!(
+            disabled,
+            &vec![json!({
+                "name": "unpack",
+                "desc": "",
+                "args": [{"name": "dir", "default": null, "list": false}],
+                "depends": [{"name": "compress", "call": "compress"}],
+                "system": false,
+                "flags": ["quiet"],
+                "feature": "#[feature(nozip)]",
+                "file": "",
+                "line": 8,
+            })]
+        );
+
+        // both lists describe recipes with the same fields
+        let keys = |v: &Value| v.as_object().unwrap().keys().cloned().collect::<Vec<String>>();
+        for r in recipes.iter().chain(disabled.iter()) {
+            assert_eq!(keys(r), keys(&recipes[1]));
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Directory to keep hashes of successfully finished recipes. The path is relative to
+/// the directory where the engine starts
+pub(crate) const CACHE_DIR: &str = ".haku/cache";
+
+/// Collects everything a recipe result depends on and builds a hash of it
+pub(crate) struct CacheKey {
+    hasher: Sha256,
+}
+
+impl CacheKey {
+    pub(crate) fn new(recipe: &str) -> Self {
+        let mut key = CacheKey { hasher: Sha256::new() };
+        key.add_str("recipe", recipe);
+        key
+    }
+
+    /// Adds a value to the hash. Every value is prefixed with its kind and length, so
+    /// different sets of values never produce the same byte stream
+    pub(crate) fn add_str(&mut self, kind: &str, val: &str) {
+        self.hasher.update(format!("{}:{}:", kind, val.len()).as_bytes());
+        self.hasher.update(val.as_bytes());
+    }
+
+    /// Adds a file path and the file content to the hash. For directories only paths are used
+    pub(crate) fn add_file(&mut self, path: &Path) -> io::Result<()> {
+        self.add_str("path", &path.to_string_lossy());
+        if path.is_file() {
+            let content = fs::read(path)?;
+            self.hasher.update(format!("file:{}:", content.len()).as_bytes());
+            self.hasher.update(&content);
+        }
+        Ok(())
+    }
+
+    /// Returns the final hash as a hexadecimal string
+    pub(crate) fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+/// Returns the path to the file that keeps the hash of the last successful recipe run
+fn entry_path(dir: &Path, recipe: &str) -> PathBuf {
+    dir.join(recipe)
+}
+
+/// Returns `true` if the last successful run of a recipe had the same hash
+pub(crate) fn is_cached(dir: &Path, recipe: &str, hash: &str) -> bool {
+    match fs::read_to_string(entry_path(dir, recipe)) {
+        Ok(s) => s.trim() == hash,
+        Err(_) => false,
+    }
+}
+
+/// Saves the hash of a successfully finished recipe
+pub(crate) fn store(dir: &Path, recipe: &str, hash: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(entry_path(dir, recipe), hash)
+}
+
+/// Removes all cached hashes
+pub(crate) fn clear(dir: &Path) -> io::Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    fs::remove_dir_all(dir)
+}
+
+#[cfg(test)]
+mod cache_test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn key() {
+        let mut k1 = CacheKey::new("build");
+        k1.add_str("cmd", "ab");
+        k1.add_str("cmd", "c");
+        let mut k2 = CacheKey::new("build");
+        k2.add_str("cmd", "a");
+        k2.add_str("cmd", "bc");
+        assert_ne!(k1.finish(), k2.finish());
+
+        let mut k1 = CacheKey::new("build");
+        k1.add_str("arg", "v1");
+        let mut k2 = CacheKey::new("build");
+        k2.add_str("arg", "v1");
+        assert_eq!(k1.finish(), k2.finish());
+    }
+
+    #[test]
+    fn store_and_clear() {
+        let dir = env::temp_dir().join(format!("haku-cache-{}", std::process::id()));
+        assert!(!is_cached(&dir, "build", "123"));
+        store(&dir, "build", "123").unwrap();
+        assert!(is_cached(&dir, "build", "123"));
+        assert!(!is_cached(&dir, "build", "1234"));
+        assert!(!is_cached(&dir, "test", "123"));
+        clear(&dir).unwrap();
+        assert!(!is_cached(&dir, "build", "123"));
+        assert!(clear(&dir).is_ok());
+    }
+}
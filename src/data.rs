@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+
+use crate::var::VarValue;
+
+/// Format of structured data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+/// Parses the content of a JSON, TOML, or YAML document and converts it to a value:
+///
+/// * objects, tables, and mappings become maps
+/// * arrays and sequences become lists
+/// * strings, integers, and booleans keep their types
+/// * floating point numbers and dates become strings
+/// * null becomes undefined value
+pub(crate) fn parse(content: &str, format: Format) -> Result<VarValue, String> {
+    match format {
+        Format::Json => serde_json::from_str(content).map(|v| from_json(&v)).map_err(|e| e.to_string()),
+        Format::Toml => content.parse::<toml::Table>().map(|t| from_toml_table(&t)).map_err(|e| e.to_string()),
+        Format::Yaml => yaml_rust2::YamlLoader::load_from_str(content)
+            .map(|docs| docs.first().map_or(VarValue::Undefined, from_yaml))
+            .map_err(|e| e.to_string()),
+    }
+}
+
+fn from_json(val: &serde_json::Value) -> VarValue {
+    use serde_json::Value;
+    match val {
+        Value::Null => VarValue::Undefined,
+        Value::Bool(b) => VarValue::Bool(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => VarValue::Int(i),
+            None => VarValue::Str(n.to_string()),
+        },
+        Value::String(s) => VarValue::Str(s.clone()),
+        Value::Array(v) => VarValue::List(v.iter().map(from_json).collect()),
+        Value::Object(m) => VarValue::Map(m.iter().map(|(k, v)| (k.clone(), from_json(v))).collect()),
+    }
+}
+
+fn from_toml_table(t: &toml::Table) -> VarValue {
+    VarValue::Map(t.iter().map(|(k, v)| (k.clone(), from_toml(v))).collect())
+}
+
+fn from_toml(val: &toml::Value) -> VarValue {
+    use toml::Value;
+    match val {
+        Value::String(s) => VarValue::Str(s.clone()),
+        Value::Integer(i) => VarValue::Int(*i),
+        Value::Float(f) => VarValue::Str(f.to_string()),
+        Value::Boolean(b) => VarValue::Bool(*b),
+        Value::Datetime(d) => VarValue::Str(d.to_string()),
+        Value::Array(v) => VarValue::List(v.iter().map(from_toml).collect()),
+        Value::Table(t) => from_toml_table(t),
+    }
+}
+
+fn from_yaml(val: &yaml_rust2::Yaml) -> VarValue {
+    use yaml_rust2::Yaml;
+    match val {
+        Yaml::Null | Yaml::BadValue | Yaml::Alias(_) => VarValue::Undefined,
+        Yaml::Boolean(b) => VarValue::Bool(*b),
+        Yaml::Integer(i) => VarValue::Int(*i),
+        Yaml::Real(s) | Yaml::String(s) => VarValue::Str(s.clone()),
+        Yaml::Array(v) => VarValue::List(v.iter().map(from_yaml).collect()),
+        Yaml::Hash(m) => {
+            let mut map = BTreeMap::new();
+            for (k, v) in m.iter() {
+                // non-string keys (e.g., `1: value`) are converted to strings
+                map.insert(from_yaml(k).to_flat_string(), from_yaml(v));
+            }
+            VarValue::Map(map)
+        }
+    }
+}
+
+/// Returns a part of a document by its path. The path is a list of keys separated with dots.
+/// A list item is selected by its index: either as a separate key or in square brackets:
+/// `dependencies.0.name` and `dependencies[0].name` are the same. An empty path returns the
+/// whole document. Returns `Undefined` if the path does not exist
+pub(crate) fn get(doc: &VarValue, path: &str) -> VarValue {
+    let mut val = doc.clone();
+    for part in path.split('.').filter(|p| !p.is_empty()) {
+        let (key, mut indices) = match part.find('[') {
+            Some(pos) => (&part[..pos], &part[pos..]),
+            None => (part, ""),
+        };
+        if !key.is_empty() {
+            val = item(&val, key);
+        }
+        while let Some(rest) = indices.strip_prefix('[') {
+            let end = match rest.find(']') {
+                Some(end) => end,
+                None => return VarValue::Undefined,
+            };
+            val = item(&val, &rest[..end]);
+            indices = &rest[end + 1..];
+        }
+    }
+    val
+}
+
+/// Selects a map value by its key or a list item by its index
+fn item(val: &VarValue, key: &str) -> VarValue {
+    match val {
+        VarValue::List(_) => match key.parse::<i64>() {
+            Ok(idx) => val.item(&VarValue::Int(idx)),
+            Err(_) => VarValue::Undefined,
+        },
+        _ => val.item(&VarValue::from(key)),
+    }
+}
+
+fn to_json_value(val: &VarValue) -> serde_json::Value {
+    use serde_json::Value;
+    match val {
+        VarValue::Undefined => Value::Null,
+        VarValue::Int(i) => Value::from(*i),
+        VarValue::Bool(b) => Value::Bool(*b),
+        VarValue::Str(_) | VarValue::Exec(_) => Value::String(val.to_string()),
+        VarValue::List(v) => Value::Array(v.iter().map(to_json_value).collect()),
+        VarValue::Map(m) => Value::Object(m.iter().map(|(k, v)| (k.clone(), to_json_value(v))).collect()),
+    }
+}
+
+/// Converts a value to a JSON string. The result of external command execution is converted
+/// to a string that contains the command output
+pub(crate) fn to_json(val: &VarValue, pretty: bool) -> String {
+    let v = to_json_value(val);
+    let res = if pretty { serde_json::to_string_pretty(&v) } else { serde_json::to_string(&v) };
+    // serializing serde_json::Value with string keys never fails
+    res.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod data_test {
+    use super::*;
+
+    #[test]
+    fn parse_and_get() {
+        let src = r#"{"package": {"name": "app", "version": "1.2.3", "private": true},
+            "deps": [{"name": "a", "ver": 1}, {"name": "b", "ver": 2.5}], "none": null}"#;
+        let doc = parse(src, Format::Json).unwrap();
+        assert_eq!(get(&doc, "package.version"), VarValue::from("1.2.3"));
+        assert_eq!(get(&doc, "package.private"), VarValue::Bool(true));
+        assert_eq!(get(&doc, "deps.1.name"), VarValue::from("b"));
+        assert_eq!(get(&doc, "deps[0].ver"), VarValue::Int(1));
+        assert_eq!(get(&doc, "deps[-1].ver"), VarValue::from("2.5"));
+        assert_eq!(get(&doc, "deps.x"), VarValue::Undefined);
+        assert_eq!(get(&doc, "none"), VarValue::Undefined);
+        assert_eq!(get(&doc, "package.missing.key"), VarValue::Undefined);
+
+        let src = "[package]\nname = \"app\"\nversion = \"0.3.5\"\nkeywords = [\"cli\", \"runner\"]\n\
+                   [dependencies]\nserde = { version = \"1\", features = [\"derive\"] }\n";
+        let doc = parse(src, Format::Toml).unwrap();
+        assert_eq!(get(&doc, "package.version"), VarValue::from("0.3.5"));
+        assert_eq!(get(&doc, "package.keywords").to_flat_string(), "cli runner");
+        assert_eq!(get(&doc, "dependencies.serde.features[0]"), VarValue::from("derive"));
+
+        let src = "name: app\nversion: 2\nlist:\n  - one\n  - two: 2\n";
+        let doc = parse(src, Format::Yaml).unwrap();
+        assert_eq!(get(&doc, "version"), VarValue::Int(2));
+        assert_eq!(get(&doc, "list.1.two"), VarValue::Int(2));
+        assert_eq!(get(&doc, ""), doc);
+        let doc = parse("base: &b {cc: gcc}\nother: *b\npi: 3.14\n1: one\n", Format::Yaml).unwrap();
+        assert_eq!(get(&doc, "other.cc"), VarValue::from("gcc"));
+        assert_eq!(get(&doc, "pi"), VarValue::from("3.14"));
+        assert_eq!(get(&doc, "1"), VarValue::from("one"));
+        assert_eq!(parse("", Format::Yaml).unwrap(), VarValue::Undefined);
+
+        assert!(parse("{", Format::Json).is_err());
+        assert!(parse("a = ", Format::Toml).is_err());
+        assert!(parse("a: [", Format::Yaml).is_err());
+    }
+
+    #[test]
+    fn json_output() {
+        let mut m = BTreeMap::new();
+        m.insert("b".to_string(), VarValue::List(vec![VarValue::Int(1), VarValue::Bool(false), VarValue::Undefined]));
+        m.insert("a".to_string(), VarValue::from("x\"y"));
+        assert_eq!(to_json(&VarValue::Map(m), false), r#"{"a":"x\"y","b":[1,false,null]}"#);
+        assert_eq!(to_json(&VarValue::from("s"), false), r#""s""#);
+        assert_eq!(to_json(&VarValue::List(Vec::new()), true), "[]");
+    }
+}
@@ -0,0 +1,204 @@
+/// Parses the content of a dotenv file and returns the list of variables in the order they
+/// are declared. Supported syntax:
+///
+/// * `NAME=value`, `export NAME=value`
+/// * empty lines and lines starting with `#` are skipped
+/// * unquoted values are trimmed, ` #` starts an inline comment
+/// * single-quoted values are used as is
+/// * double-quoted values can span a few lines and can contain escaped characters: `\n`,
+///   `\t`, `\r`, `\"`, `\\`, and `\$`. In unquoted values only `\$` is escaped
+/// * `${NAME}`, `$NAME`, and `${NAME:-default}` in unquoted and double-quoted values are
+///   replaced with the value of the variable declared earlier in the same file, or with
+///   the value `lookup` returns for the name. Unknown variables are replaced with an empty
+///   string (or with the default value)
+///
+/// Returns an error with the line number if a line cannot be parsed
+pub(crate) fn parse<F>(content: &str, lookup: F) -> Result<Vec<(String, String)>, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut vars: Vec<(String, String)> = Vec::new();
+    let lines: Vec<&str> = content.lines().collect();
+    let mut idx = 0;
+    while idx < lines.len() {
+        let line_no = idx + 1;
+        let line = lines[idx].trim();
+        idx += 1;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = match line.strip_prefix("export") {
+            Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim_start(),
+            _ => line,
+        };
+        let (name, value) = match line.split_once('=') {
+            Some((n, v)) => (n.trim(), v.trim_start()),
+            None => return Err(format!("line {}: '=' expected", line_no)),
+        };
+        if !is_valid_name(name) {
+            return Err(format!("line {}: invalid variable name '{}'", line_no, name));
+        }
+
+        let get = |n: &str| -> Option<String> {
+            match vars.iter().rev().find(|(k, _)| k == n) {
+                Some((_, v)) => Some(v.clone()),
+                None => lookup(n),
+            }
+        };
+        let value = if let Some(rest) = value.strip_prefix('\'') {
+            match rest.find('\'') {
+                Some(end) => rest[..end].to_string(),
+                None => return Err(format!("line {}: unterminated single-quoted value", line_no)),
+            }
+        } else if let Some(rest) = value.strip_prefix('"') {
+            // a double-quoted value may continue on the next lines
+            let mut raw = rest.to_string();
+            let end = loop {
+                if let Some(end) = closing_quote(&raw) {
+                    break end;
+                }
+                if idx >= lines.len() {
+                    return Err(format!("line {}: unterminated double-quoted value", line_no));
+                }
+                raw.push('\n');
+                raw += lines[idx];
+                idx += 1;
+            };
+            expand(&raw[..end], &get, true)
+        } else {
+            let value = match value.find(" #") {
+                Some(pos) => &value[..pos],
+                None => value,
+            };
+            expand(value.trim_end(), &get, false)
+        };
+        vars.push((name.to_string(), value));
+    }
+    Ok(vars)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Returns the position of the first double quote that is not escaped with a backslash
+fn closing_quote(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (pos, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(pos),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Replaces `$NAME`, `${NAME}`, and `${NAME:-default}` with variable values. If `escapes`
+/// is `true` (double-quoted values), escaped characters are replaced as well. Otherwise,
+/// only `\$` is replaced with `$`, and other backslashes are kept as is
+fn expand<F>(s: &str, get: &F, escapes: bool) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut res = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find(['$', '\\']) {
+        res += &rest[..pos];
+        let tail = &rest[pos..];
+        if let Some(t) = tail.strip_prefix('\\') {
+            let mut chars = t.chars();
+            match chars.next() {
+                Some('$') => res.push('$'),
+                Some('n') if escapes => res.push('\n'),
+                Some('t') if escapes => res.push('\t'),
+                Some('r') if escapes => res.push('\r'),
+                Some(c) if escapes => res.push(c),
+                _ => {
+                    res.push('\\');
+                    rest = t;
+                    continue;
+                }
+            }
+            rest = chars.as_str();
+        } else if let Some(t) = tail.strip_prefix("${") {
+            match t.find('}') {
+                Some(end) => {
+                    let inner = &t[..end];
+                    let (name, default) = match inner.split_once(":-") {
+                        Some((n, d)) => (n, Some(d)),
+                        None => (inner, None),
+                    };
+                    match get(name) {
+                        Some(v) if !v.is_empty() || default.is_none() => res += &v,
+                        _ => res += &expand(default.unwrap_or_default(), get, escapes),
+                    }
+                    rest = &t[end + 1..];
+                }
+                None => {
+                    res += tail;
+                    rest = "";
+                }
+            }
+        } else {
+            let t = &tail[1..];
+            let len = t.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(t.len());
+            if len == 0 {
+                res.push('$');
+            } else {
+                res += &get(&t[..len]).unwrap_or_default();
+            }
+            rest = &t[len..];
+        }
+    }
+    res += rest;
+    res
+}
+
+#[cfg(test)]
+mod dotenv_test {
+    use super::*;
+
+    #[test]
+    fn parse_vars() {
+        let src = r#"
+# comment
+PLAIN=value
+export EXPORTED = spaced value  # inline comment
+SINGLE='no $PLAIN expansion # here'
+DOUBLE="line1\nline2 \"q\" \$PLAIN \\$PLAIN"
+MULTI="first
+second"
+BRACES=${PLAIN}-$PLAIN-${HOME_DIR}
+DEFAULT=${MISSING:-fallback}
+EMPTY=
+"#;
+        let lookup = |n: &str| if n == "HOME_DIR" { Some("/home/me".to_string()) } else { None };
+        let vars = parse(src, lookup).unwrap();
+        let get = |n: &str| vars.iter().find(|(k, _)| k == n).map(|(_, v)| v.as_str());
+        assert_eq!(get("PLAIN"), Some("value"));
+        assert_eq!(get("EXPORTED"), Some("spaced value"));
+        assert_eq!(get("SINGLE"), Some("no $PLAIN expansion # here"));
+        assert_eq!(get("DOUBLE"), Some("line1\nline2 \"q\" $PLAIN \\value"));
+        assert_eq!(get("MULTI"), Some("first\nsecond"));
+        assert_eq!(get("BRACES"), Some("value-value-/home/me"));
+        assert_eq!(get("DEFAULT"), Some("fallback"));
+        assert_eq!(get("EMPTY"), Some(""));
+        assert_eq!(vars.len(), 8);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let none = |_: &str| None;
+        assert!(parse("NAME", none).is_err());
+        assert!(parse("1NAME=a", none).is_err());
+        assert!(parse("A='open", none).is_err());
+        assert!(parse("A=\"open\nB=c", none).is_err());
+    }
+}
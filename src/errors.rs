@@ -1,7 +1,6 @@
-use std::usize;
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum HakuError {
     #[error("Default recipe not found")]
     DefaultRecipeError,
@@ -13,6 +12,8 @@ pub enum HakuError {
     FileOpenFailure(String, String),
     #[error("Failed to read file '{0}'")]
     FileReadFailure(String),
+    #[error("Failed to load environment file '{0}': {1}")]
+    EnvFileError(String, String),
     #[error("File '{0}' does not exists")]
     FileNotLoaded(usize),
     #[error("Fail to parse '{0}'{1}")]
@@ -33,6 +34,12 @@ pub enum HakuError {
     RecipeDisabledError(String),
     #[error("Failed to execute '{0}': {1}{2}")]
     ExecFailureError(String, String, String),
+    #[error("Failed to execute '{0}': (exit code: {1}){2}")]
+    ExecExitError(String, i32, String),
+    #[error("Command '{0}' timed out after {1}{2}")]
+    TimeoutError(String, String, String),
+    #[error("Recipe '{0}' interrupted by signal {1}{2}")]
+    Interrupted(String, i32, String),
     #[error("Function call error: '{0}'")]
     FunctionError(String),
     #[error("Include inside a recipe is not supported{0}")]
@@ -49,15 +56,95 @@ pub enum HakuError {
     StrayElseError(String),
     #[error("'ELSEIF' without corresponding IF{0}")]
     StrayElseIfError(String),
-    #[error("Only the last recipe argument can be a list: '{0}'")]
+    #[error("'{0}' without corresponding TRY{1}")]
+    StrayTryPartError(String, String),
+    #[error("Only the last recipe argument can be a list and it cannot have a default value: '{0}'")]
     RecipeListArgError(String),
+    #[error("Recipe '{0}' requires arguments: {1}")]
+    RecipeArgMissingError(String, String),
+    #[error("Function '{0}' expects {1} arguments, {2} given{3}")]
+    FuncArgCountError(String, usize, usize, String),
+    #[error("Function '{0}' is nested too deep{1}")]
+    FuncRecursionError(String, String),
+    #[error("Integer overflow in '{0}'{1}")]
+    OverflowError(String, String),
+    #[error("Division by zero{0}")]
+    DivByZeroError(String),
     #[error("Execution interrupted with message: {0}")]
     UserError(String),
     #[error("Invalid directory {0}: {1}")]
     CdError(String, String),
+    #[error("Failed to clear cache '{0}': {1}")]
+    CacheError(String, String),
 }
 
 impl HakuError {
+    /// Returns a number that identifies the kind of the error. The numbers are stable: a kind
+    /// never changes its number in future versions, and new kinds get new numbers. All
+    /// numbers are less than 128, so they can be used as a process exit code:
+    ///
+    /// * 1 - internal error
+    /// * 10-19 - failed to load or parse a script
+    /// * 20-29 - a recipe cannot be found or started
+    /// * 30-39 - an external command, or an action requested by a script failed
+    /// * 40-59 - script runtime errors
+    pub fn kind(&self) -> i32 {
+        match self {
+            HakuError::InternalError(_) => 1,
+
+            HakuError::FileOpenFailure(_, _) => 10,
+            HakuError::FileReadFailure(_) => 11,
+            HakuError::FileNotLoaded(_) => 12,
+            HakuError::ParseError(_, _) => 13,
+            HakuError::IncludeRecursionError(_) => 14,
+            HakuError::InvalidFeatureName(_, _) => 15,
+            HakuError::RecipeListArgError(_) => 16,
+            HakuError::EnvFileError(_, _) => 17,
+
+            HakuError::DefaultRecipeError => 20,
+            HakuError::RecipeNotFoundError(_) => 21,
+            HakuError::RecipeDisabledError(_) => 22,
+            HakuError::RecipeRecursionError(_, _) => 23,
+            HakuError::RecipeArgMissingError(_, _) => 24,
+
+            HakuError::ExecFailureError(_, _, _) => 30,
+            HakuError::ExecExitError(_, _, _) => 31,
+            HakuError::CdError(_, _) => 32,
+            HakuError::UserError(_) => 33,
+            HakuError::CacheError(_, _) => 34,
+            HakuError::TimeoutError(_, _, _) => 35,
+            HakuError::Interrupted(_, _, _) => 36,
+
+            HakuError::FunctionError(_) => 40,
+            HakuError::FuncArgCountError(_, _, _, _) => 41,
+            HakuError::FuncRecursionError(_, _) => 42,
+            HakuError::OverflowError(_, _) => 43,
+            HakuError::DivByZeroError(_) => 44,
+            HakuError::IncludeInRecipeError(_) => 45,
+            HakuError::StrayEndError(_) => 46,
+            HakuError::StrayElseError(_) => 47,
+            HakuError::StrayElseIfError(_) => 48,
+            HakuError::NoMatchingEndError(_, _) => 49,
+            HakuError::NoMatchingForWhileError(_) => 50,
+            HakuError::ForeverForError(_) => 51,
+            HakuError::SeqIntError(_, _) => 52,
+            HakuError::SeqError(_, _, _) => 53,
+            HakuError::SeqVarNameError(_) => 54,
+            HakuError::StrayTryPartError(_, _) => 55,
+        }
+    }
+
+    /// Returns the exit code of an external command if the error is caused by the command
+    /// that finished with non-zero exit code. For an interrupted script it is the code a
+    /// shell reports for a process killed by the signal: 128 plus the signal number
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            HakuError::ExecExitError(_, code, _) => Some(*code),
+            HakuError::Interrupted(_, sig, _) => Some(128 + *sig),
+            _ => None,
+        }
+    }
+
     /// Generates detailed information about a place where the error happenned.
     pub(crate) fn error_extra(filename: &str, line: &str, line_no: usize) -> String {
         if !filename.is_empty() && !line.is_empty() {
@@ -1,5 +1,7 @@
+use std::cmp::Ordering;
 use std::env;
 use std::ffi::OsStr;
+use std::fs;
 use std::path::{Path, PathBuf};
 
 use glob::glob;
@@ -9,6 +11,7 @@ use semver::{Version, VersionReq};
 use target::{arch, endian, os, os_family, pointer_width};
 use unicode_width::UnicodeWidthStr;
 
+use crate::data::{self, Format};
 use crate::var::VarValue;
 use crate::vm::Engine;
 
@@ -125,6 +128,21 @@ pub(crate) fn run_func(name: &str, eng: &mut Engine, args: &[VarValue]) -> FuncR
         "field" | "fields" => fields(args),
         "field-sep" | "fields-sep" | "field_sep" | "fields_sep" => fields_with_sep(args),
         "rand-str" | "rand_str" => rand_string(args),
+        "len" => list_len(args),
+        "push" => list_push(args),
+        "pop" => list_pop(args),
+        "sort" => list_sort(args),
+        "uniq" => list_uniq(args),
+        "reverse" => list_reverse(args),
+        "join-list" | "join_list" => list_join(args),
+        "split" => split_str(args),
+        "filter-match" | "filter_match" => list_filter(args),
+        "map-replace" | "map_replace" => list_replace(args),
+        "index-of" | "index_of" => list_index_of(args),
+        "slice" => list_slice(args),
+        "exit-code" | "exit_code" => exec_exit_code(args),
+        "stdout" => exec_stdout(args),
+        "stderr" => exec_stderr(args),
         "inc" => increment(args),
         "dec" => decrement(args),
         "shell" => change_shell(eng, args),
@@ -137,12 +155,17 @@ pub(crate) fn run_func(name: &str, eng: &mut Engine, args: &[VarValue]) -> FuncR
         "set-env" | "set_env" | "setenv" => set_env_var(eng, args),
         "del-env" | "del_env" | "delenv" => del_env_var(eng, args),
         "clear-env" | "clear_env" | "clearenv" => eng.clear_env_vars(),
+        "env-file" | "env_file" | "envfile" => load_env_files(eng, args),
         "glob" => globfiles(args),
         "ver-inc" | "ver_inc" => semver_inc(args),
         "ver-eq" | "ver_eq" => semver_equal(args),
         "ver-gt" | "ver_gt" => semver_greater(args),
         "ver-lt" | "ver_lt" => semver_less(args),
         "ver-match" | "ver_match" => semver_match(args),
+        "json-get" | "json_get" => data_get(eng, args, Format::Json),
+        "toml-get" | "toml_get" => data_get(eng, args, Format::Toml),
+        "yaml-get" | "yaml_get" => data_get(eng, args, Format::Yaml),
+        "to-json" | "to_json" => to_json(args),
         _ => Err(format!("function {} not found", name)),
     }
 }
@@ -165,6 +188,57 @@ fn del_env_var(eng: &mut Engine, args: &[VarValue]) -> FuncResult {
     eng.del_env_var(name)
 }
 
+/// Loads variables from dotenv files into the script environment. Returns the total number
+/// of loaded variables
+fn load_env_files(eng: &mut Engine, args: &[VarValue]) -> FuncResult {
+    let mut cnt = 0;
+    for path in args.iter().map(|v| v.to_string()).filter(|a| !a.is_empty()) {
+        cnt += eng.load_env_file(&path).map_err(|e| e.to_string())?;
+    }
+    Ok(VarValue::Int(cnt as i64))
+}
+
+/// Returns `true` if a single-line argument of `data_get` is a document, not a file path:
+/// a JSON or YAML object or list, or a TOML key-value pair
+fn is_inline_doc(src: &str, format: Format) -> bool {
+    let s = src.trim_start();
+    s.starts_with('{') || s.starts_with('[') || (format == Format::Toml && s.contains('='))
+}
+
+/// Reads a value from a JSON, TOML, or YAML document. The first argument is a path to a file
+/// or the document itself. The second optional argument is the path to the value: keys
+/// separated with dots. If the path is omitted, the function returns the whole document
+fn data_get(eng: &Engine, args: &[VarValue], format: Format) -> FuncResult {
+    if args.is_empty() {
+        return Err("requires at least one argument".to_string());
+    }
+    let src = args[0].to_string();
+    let path = eng.full_path(&src);
+    let content = if src.contains('\n') || (!path.is_file() && is_inline_doc(&src, format)) {
+        src
+    } else if !path.exists() {
+        return Err(format!("file not found: {}", src));
+    } else {
+        match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("failed to read {}: {}", src, e)),
+        }
+    };
+    let doc = data::parse(&content, format)?;
+    let key = if args.len() > 1 { args[1].to_string() } else { String::new() };
+    Ok(data::get(&doc, &key))
+}
+
+/// Converts a value to a JSON string. If the second argument is truthy, the JSON is
+/// pretty-printed
+fn to_json(args: &[VarValue]) -> FuncResult {
+    if args.is_empty() {
+        return Ok(VarValue::from("null"));
+    }
+    let pretty = args.len() > 1 && args[1].is_true();
+    Ok(VarValue::Str(data::to_json(&args[0], pretty)))
+}
+
 /// Checks if all paths are the same: files, directories, existing filesystem objects
 fn all_are(args: &[VarValue], tp: CheckType) -> FuncResult {
     if args.is_empty() {
@@ -195,10 +269,10 @@ fn extract_part(args: &[VarValue], tp: PathPart) -> FuncResult {
     let empty = OsStr::new("");
     let empty_path = Path::new("");
     match tp {
-        PathPart::Stem => Ok(VarValue::from(p.file_stem().unwrap_or(&empty).to_string_lossy().to_string())),
-        PathPart::Ext => Ok(VarValue::from(p.extension().unwrap_or(&empty).to_string_lossy().to_string())),
-        PathPart::Dir => Ok(VarValue::from(p.parent().unwrap_or(&empty_path).to_string_lossy().to_string())),
-        PathPart::Name => Ok(VarValue::from(p.file_name().unwrap_or(&empty).to_string_lossy().to_string())),
+        PathPart::Stem => Ok(VarValue::from(p.file_stem().unwrap_or(empty).to_string_lossy().to_string())),
+        PathPart::Ext => Ok(VarValue::from(p.extension().unwrap_or(empty).to_string_lossy().to_string())),
+        PathPart::Dir => Ok(VarValue::from(p.parent().unwrap_or(empty_path).to_string_lossy().to_string())),
+        PathPart::Name => Ok(VarValue::from(p.file_name().unwrap_or(empty).to_string_lossy().to_string())),
     }
 }
 
@@ -285,8 +359,8 @@ fn replace_stem(args: &[VarValue]) -> FuncResult {
     }
     let empty = OsStr::new("");
     let empty_path = Path::new("");
-    let ext = p.extension().unwrap_or(&empty).to_string_lossy().to_string();
-    let dir = p.parent().unwrap_or(&empty_path);
+    let ext = p.extension().unwrap_or(empty).to_string_lossy().to_string();
+    let dir = p.parent().unwrap_or(empty_path);
     let fname = if ext.is_empty() { new_stem } else { new_stem + "." + &ext };
     Ok(VarValue::Str(dir.join(fname).to_string_lossy().to_string()))
 }
@@ -331,7 +405,7 @@ fn system_path(pathtype: SysPath) -> FuncResult {
 /// outputs `\n` at the end.
 fn print_all(args: &[VarValue], add_new_line: bool) -> FuncResult {
     for v in args.iter() {
-        print!("{}", v.to_string());
+        print!("{}", v);
     }
     if add_new_line {
         println!();
@@ -537,13 +611,13 @@ fn pad(args: &[VarValue], loc: Where) -> FuncResult {
     }
 
     let patt = args[1].to_string();
-    let patt_width = patt.width() as usize;
+    let patt_width = patt.width();
     if patt_width == 0 {
         return Err("pad string cannot be empty".to_string());
     }
     let l = args[2].to_int() as usize;
     let s = args[0].to_string();
-    let orig_width = s.width() as usize;
+    let orig_width = s.width();
 
     if orig_width + patt_width >= l {
         return Ok(VarValue::from(s));
@@ -590,7 +664,7 @@ fn fields(args: &[VarValue]) -> FuncResult {
     if args.len() == 2 {
         Ok(VarValue::from(vals.pop().unwrap())) // unwrap is OK - vals is never empty here
     } else {
-        Ok(VarValue::List(vals))
+        Ok(VarValue::List(vals.into_iter().map(VarValue::Str).collect()))
     }
 }
 
@@ -626,8 +700,178 @@ fn fields_with_sep(args: &[VarValue]) -> FuncResult {
     if args.len() == 3 {
         Ok(VarValue::from(vals.pop().unwrap())) // unwrap is OK - vals is never empty here
     } else {
-        Ok(VarValue::List(vals))
+        Ok(VarValue::List(vals.into_iter().map(VarValue::Str).collect()))
+    }
+}
+
+/// Returns the number of items of a list or a map. For other values it returns the length of
+/// the value converted to a string in UTF8 characters.
+fn list_len(args: &[VarValue]) -> FuncResult {
+    if args.is_empty() {
+        return Ok(VarValue::Int(0));
     }
+    let cnt = match &args[0] {
+        VarValue::Undefined => 0,
+        VarValue::List(v) => v.len(),
+        VarValue::Map(m) => m.len(),
+        v => v.to_string().chars().count(),
+    };
+    Ok(VarValue::Int(cnt as i64))
+}
+
+/// Returns a new list: the first argument with the rest arguments appended to its end.
+/// A list argument is appended as a single item
+fn list_push(args: &[VarValue]) -> FuncResult {
+    if args.is_empty() {
+        return Ok(VarValue::List(Vec::new()));
+    }
+    let mut v = args[0].to_list();
+    v.extend_from_slice(&args[1..]);
+    Ok(VarValue::List(v))
+}
+
+/// Returns a new list: the first argument without its last item. The second optional argument
+/// is the number of items to remove (the default value is `1`)
+fn list_pop(args: &[VarValue]) -> FuncResult {
+    if args.is_empty() {
+        return Ok(VarValue::List(Vec::new()));
+    }
+    let mut v = args[0].to_list();
+    let cnt = if args.len() > 1 { args[1].to_int().max(0) as usize } else { 1 };
+    v.truncate(v.len().saturating_sub(cnt));
+    Ok(VarValue::List(v))
+}
+
+/// Compares list items: integers and booleans are compared as numbers, other values are
+/// compared as strings
+fn cmp_items(a: &VarValue, b: &VarValue) -> Ordering {
+    match (a, b) {
+        (VarValue::Int(_) | VarValue::Bool(_), VarValue::Int(_) | VarValue::Bool(_)) => a.to_int().cmp(&b.to_int()),
+        _ => a.to_flat_string().cmp(&b.to_flat_string()),
+    }
+}
+
+/// Returns a new sorted list
+fn list_sort(args: &[VarValue]) -> FuncResult {
+    if args.is_empty() {
+        return Ok(VarValue::List(Vec::new()));
+    }
+    let mut v = args[0].to_list();
+    v.sort_by(cmp_items);
+    Ok(VarValue::List(v))
+}
+
+/// Returns a new list without duplicated items. The first occurrence of an item is kept
+fn list_uniq(args: &[VarValue]) -> FuncResult {
+    if args.is_empty() {
+        return Ok(VarValue::List(Vec::new()));
+    }
+    let mut v: Vec<VarValue> = Vec::new();
+    for item in args[0].to_list().into_iter() {
+        if !v.iter().any(|it| it.cmp(&item, "==")) {
+            v.push(item);
+        }
+    }
+    Ok(VarValue::List(v))
+}
+
+/// Returns a new list with items in reverse order
+fn list_reverse(args: &[VarValue]) -> FuncResult {
+    if args.is_empty() {
+        return Ok(VarValue::List(Vec::new()));
+    }
+    let mut v = args[0].to_list();
+    v.reverse();
+    Ok(VarValue::List(v))
+}
+
+/// Joins list items (the first argument) into a string using a separator (the second
+/// argument). If the separator is omitted, items are joined with a space
+fn list_join(args: &[VarValue]) -> FuncResult {
+    if args.is_empty() {
+        return Ok(VarValue::from(""));
+    }
+    let sep = if args.len() > 1 { args[1].to_string() } else { " ".to_string() };
+    let v: Vec<String> = args[0].to_list().iter().map(|it| it.to_flat_string()).collect();
+    Ok(VarValue::Str(v.join(&sep)))
+}
+
+/// Splits a string (the first argument) at a separator (the second argument) and returns
+/// a list of strings. If the separator is omitted or empty, the string is split at whitespace
+fn split_str(args: &[VarValue]) -> FuncResult {
+    if args.is_empty() {
+        return Ok(VarValue::List(Vec::new()));
+    }
+    let s = args[0].to_string();
+    let sep = if args.len() > 1 { args[1].to_string() } else { String::new() };
+    let v: Vec<VarValue> = if sep.is_empty() {
+        s.split_whitespace().map(VarValue::from).collect()
+    } else {
+        s.split(&sep).map(VarValue::from).collect()
+    };
+    Ok(VarValue::List(v))
+}
+
+/// Returns a new list of items (the first argument) that match a regular expression (the
+/// second argument)
+fn list_filter(args: &[VarValue]) -> FuncResult {
+    if args.len() < 2 {
+        return Err("requires two arguments".to_string());
+    }
+    let rx = match Regex::new(&args[1].to_string()) {
+        Err(e) => return Err(e.to_string()),
+        Ok(rg) => rg,
+    };
+    let v: Vec<VarValue> = args[0].to_list().into_iter().filter(|it| rx.is_match(&it.to_flat_string())).collect();
+    Ok(VarValue::List(v))
+}
+
+/// Returns a new list of strings: in every item of a list (the first argument) a substring
+/// (the second argument) is replaced with another substring (the third argument). If the third
+/// argument is missing, the function just removes the substring
+fn list_replace(args: &[VarValue]) -> FuncResult {
+    if args.len() < 2 {
+        return Err("requires at least two arguments".to_string());
+    }
+    let what = args[1].to_string();
+    let with = if args.len() > 2 { args[2].to_string() } else { String::new() };
+    let v: Vec<VarValue> =
+        args[0].to_list().iter().map(|it| VarValue::Str(it.to_flat_string().replace(&what, &with))).collect();
+    Ok(VarValue::List(v))
+}
+
+/// Returns the index of the first list item (the first argument) that equals a value (the
+/// second argument), or `-1` if the list does not contain the value
+fn list_index_of(args: &[VarValue]) -> FuncResult {
+    if args.len() < 2 {
+        return Err("requires two arguments".to_string());
+    }
+    match args[0].to_list().iter().position(|it| it.cmp(&args[1], "==")) {
+        Some(idx) => Ok(VarValue::Int(idx as i64)),
+        None => Ok(VarValue::Int(-1)),
+    }
+}
+
+/// Returns a new list that contains items of a list (the first argument) starting from index
+/// `start`(the second argument) and up to, but not including, index `end`(the third argument).
+/// If `end` is omitted, the slice lasts until the end of the list. Negative indices count from
+/// the end of the list. Indices out of list bounds are clamped
+fn list_slice(args: &[VarValue]) -> FuncResult {
+    if args.len() < 2 {
+        return Err("requires at least two arguments".to_string());
+    }
+    let v = args[0].to_list();
+    let len = v.len() as i64;
+    let clamp = |i: i64| -> usize {
+        let i = if i < 0 { i + len } else { i };
+        i.max(0).min(len) as usize
+    };
+    let start = clamp(args[1].to_int());
+    let end = if args.len() > 2 { clamp(args[2].to_int()) } else { v.len() };
+    if start >= end {
+        return Ok(VarValue::List(Vec::new()));
+    }
+    Ok(VarValue::List(v[start..end].to_vec()))
 }
 
 /// Generates a random string of a given length. First argument is the length of the string.
@@ -685,6 +929,36 @@ fn increment(args: &[VarValue]) -> FuncResult {
     Ok(VarValue::Int(val))
 }
 
+/// Returns the exit code of an external command execution result. Other values, except
+/// undefined one, are considered successful results and return `0`. Undefined value means that
+/// the command failed to start, so it returns `-1`
+fn exec_exit_code(args: &[VarValue]) -> FuncResult {
+    match args.first() {
+        None | Some(VarValue::Undefined) => Ok(VarValue::Int(-1)),
+        Some(VarValue::Exec(er)) => Ok(VarValue::from(er.code)),
+        Some(_) => Ok(VarValue::Int(0)),
+    }
+}
+
+/// Returns the standard output of an external command execution result, even if the command
+/// failed. Other values are converted to a string
+fn exec_stdout(args: &[VarValue]) -> FuncResult {
+    match args.first() {
+        None => Ok(VarValue::from("")),
+        Some(VarValue::Exec(er)) => Ok(VarValue::from(er.stdout.clone())),
+        Some(v) => Ok(VarValue::from(v.to_string())),
+    }
+}
+
+/// Returns the error output of an external command execution result. Other values have
+/// empty error output
+fn exec_stderr(args: &[VarValue]) -> FuncResult {
+    match args.first() {
+        Some(VarValue::Exec(er)) => Ok(VarValue::from(er.stderr.clone())),
+        _ => Ok(VarValue::from("")),
+    }
+}
+
 /// Returns decremented value. If only one argument is provided, it is decrements it by one.
 /// Otherwise it subtracts all values(except the first one) from the first one.
 /// NOTE: all values are converted into integers.
@@ -720,20 +994,18 @@ fn globfiles(args: &[VarValue]) -> FuncResult {
         Err(e) => return Err(e.to_string()),
     };
 
-    for entry in entries {
-        if let Ok(p) = entry {
-            if globtype == 1 && !p.is_file() {
-                continue;
-            }
-            if globtype == 2 && !p.is_dir() {
-                continue;
-            }
-            let s = p.to_string_lossy();
-            v.push(s.to_string());
+    for p in entries.flatten() {
+        if globtype == 1 && !p.is_file() {
+            continue;
         }
+        if globtype == 2 && !p.is_dir() {
+            continue;
+        }
+        let s = p.to_string_lossy();
+        v.push(s.to_string());
     }
 
-    Ok(VarValue::List(v))
+    Ok(VarValue::List(v.into_iter().map(VarValue::Str).collect()))
 }
 
 /// Compare two semantic versions with operator `op` and return the result.
@@ -838,6 +1110,26 @@ fn semver_inc(args: &[VarValue]) -> FuncResult {
 #[cfg(test)]
 mod path_test {
     use super::*;
+    use crate::vm::RunOpts;
+
+    #[test]
+    fn data_source() {
+        let eng = Engine::new(RunOpts::new());
+        let args = vec![VarValue::from(r#"{"a": {"b": 2}}"#), VarValue::from("a.b")];
+        assert_eq!(data_get(&eng, &args, Format::Json), Ok(VarValue::Int(2)));
+        let args = vec![VarValue::from("x = 1"), VarValue::from("x")];
+        assert_eq!(data_get(&eng, &args, Format::Toml), Ok(VarValue::Int(1)));
+        let args = vec![VarValue::from("a: 1\nb: 2"), VarValue::from("b")];
+        assert_eq!(data_get(&eng, &args, Format::Yaml), Ok(VarValue::Int(2)));
+        let args = vec![VarValue::from("Cargo.toml"), VarValue::from("package.name")];
+        assert_eq!(data_get(&eng, &args, Format::Toml), Ok(VarValue::from("haku")));
+        for (src, format) in [("package.jsn", Format::Json), ("config.yml", Format::Yaml), ("a: 1", Format::Yaml)] {
+            match data_get(&eng, &[VarValue::from(src)], format) {
+                Err(e) => assert!(e.starts_with("file not found"), "{}", e),
+                Ok(v) => panic!("{} must fail, got {:?}", src, v),
+            }
+        }
+    }
 
     #[test]
     fn extract() {
@@ -1100,7 +1392,7 @@ mod path_test {
         assert_eq!(r, Ok(VarValue::from("")));
         let v = vec![VarValue::from("abc def\tghi"), VarValue::from(1), VarValue::from(2), VarValue::from(1)];
         let r = fields(&v);
-        assert_eq!(r, Ok(VarValue::List(vec!["def".to_string(), "ghi".to_string(), "def".to_string()])));
+        assert_eq!(r, Ok(VarValue::List(vec![VarValue::from("def"), VarValue::from("ghi"), VarValue::from("def")])));
 
         // with separator
         let v = vec![VarValue::from("abc daf\tahi")];
@@ -1115,10 +1407,46 @@ mod path_test {
         assert_eq!(r, Ok(VarValue::from("")));
         let v = vec![VarValue::from("abc daf\tahi"), VarValue::from("a"), VarValue::from(2), VarValue::from("1")];
         let r = fields_with_sep(&v);
-        assert_eq!(r, Ok(VarValue::List(vec!["f\t".to_string(), "bc d".to_string()])));
+        assert_eq!(r, Ok(VarValue::List(vec![VarValue::from("f\t"), VarValue::from("bc d")])));
         let v = vec![VarValue::from("abc daf\tahi"), VarValue::from("a"), VarValue::from(1), VarValue::from("asd")];
         let r = fields_with_sep(&v);
-        assert_eq!(r, Ok(VarValue::List(vec!["bc d".to_string(), String::new()])));
+        assert_eq!(r, Ok(VarValue::List(vec![VarValue::from("bc d"), VarValue::from("")])));
+    }
+
+    #[test]
+    fn lists() {
+        let l =
+            VarValue::List(vec![VarValue::from("b.rs"), VarValue::Int(10), VarValue::from("a.txt"), VarValue::Int(2)]);
+        let one = vec![l.clone()];
+        let strs = |v: FuncResult| -> Vec<String> {
+            match v {
+                Ok(VarValue::List(v)) => v.iter().map(|it| it.to_string()).collect(),
+                v => panic!("list expected, got {:?}", v),
+            }
+        };
+        assert_eq!(list_len(&one), Ok(VarValue::Int(4)));
+        assert_eq!(list_len(&[VarValue::from("abc")]), Ok(VarValue::Int(3)));
+        assert_eq!(strs(list_push(&[l.clone(), VarValue::from("c")])), vec!["b.rs", "10", "a.txt", "2", "c"]);
+        assert_eq!(strs(list_pop(&one)), vec!["b.rs", "10", "a.txt"]);
+        assert_eq!(strs(list_pop(&[l.clone(), VarValue::Int(10)])).len(), 0);
+        assert_eq!(strs(list_sort(&one)), vec!["2", "10", "a.txt", "b.rs"]);
+        assert_eq!(strs(list_reverse(&one)), vec!["2", "a.txt", "10", "b.rs"]);
+        let dup = VarValue::List(vec![VarValue::from("1"), VarValue::Int(1), VarValue::from("a"), VarValue::from("1")]);
+        assert_eq!(strs(list_uniq(&[dup])), vec!["1", "a"]);
+        assert_eq!(list_join(&[l.clone(), VarValue::from(",")]), Ok(VarValue::from("b.rs,10,a.txt,2")));
+        assert_eq!(strs(split_str(&[VarValue::from("a,b,,c"), VarValue::from(",")])), vec!["a", "b", "", "c"]);
+        assert_eq!(strs(split_str(&[VarValue::from(" a  b ")])), vec!["a", "b"]);
+        assert_eq!(strs(list_filter(&[l.clone(), VarValue::from(r"\.(rs|txt)$")])), vec!["b.rs", "a.txt"]);
+        assert!(list_filter(&[l.clone(), VarValue::from("(")]).is_err());
+        assert_eq!(
+            strs(list_replace(&[l.clone(), VarValue::from("."), VarValue::from("_")])),
+            vec!["b_rs", "10", "a_txt", "2"]
+        );
+        assert_eq!(list_index_of(&[l.clone(), VarValue::from("10")]), Ok(VarValue::Int(1)));
+        assert_eq!(list_index_of(&[l.clone(), VarValue::from("x")]), Ok(VarValue::Int(-1)));
+        assert_eq!(strs(list_slice(&[l.clone(), VarValue::Int(1), VarValue::Int(3)])), vec!["10", "a.txt"]);
+        assert_eq!(strs(list_slice(&[l.clone(), VarValue::Int(-2)])), vec!["a.txt", "2"]);
+        assert_eq!(strs(list_slice(&[l, VarValue::Int(3), VarValue::Int(1)])).len(), 0);
     }
 
     #[test]
@@ -1132,7 +1460,7 @@ mod path_test {
         let v = vec![VarValue::from(10), VarValue::from("0123456789")];
         let s1 = rand_string(&v);
         for chr in s1.unwrap().to_string().chars() {
-            assert!(chr >= '0' && chr <= '9');
+            assert!(chr.is_ascii_digit());
         }
     }
 
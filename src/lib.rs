@@ -1,8 +1,15 @@
+mod cache;
+mod data;
+mod dotenv;
 pub mod errors;
 mod feature;
 mod func;
 mod ops;
 mod parse;
+mod signal;
+mod stream;
+mod timeout;
+mod uptodate;
 pub mod var;
 pub mod vm;
 
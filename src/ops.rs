@@ -1,4 +1,4 @@
-use std::i64;
+use std::fmt;
 
 use pest::iterators::{Pair, Pairs};
 
@@ -18,15 +18,59 @@ pub enum Seq {
     Exec(String),
     /// a value of a variable
     Var(String),
+    /// a list or a map literal, or an item of a list or a map
+    Value(Box<Op>),
 }
 
-/// external command and recipe flags. Flags are added as prefixes of a script lines.
+// external command and recipe flags. Flags are added as prefixes of a script lines.
 
 /// Do not print the command before execution (`@`)
 pub const FLAG_QUIET: u32 = 1;
 /// Do not interrupt the execution if external command has failed(`-`)
 pub const FLAG_PASS: u32 = 2;
 
+/// Recipe argument declaration: `name`, `name="default"`, or `+name`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecipeArg {
+    /// argument name (without leading `+`)
+    pub name: String,
+    /// value used when the argument is not passed
+    pub default: Option<String>,
+    /// the argument collects all the rest free arguments (declared with leading `+`)
+    pub list: bool,
+}
+
+impl fmt::Display for RecipeArg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.list {
+            write!(f, "+")?;
+        }
+        write!(f, "{}", self.name)?;
+        if let Some(ref d) = self.default {
+            write!(f, "=\"{}\"", d)?;
+        }
+        Ok(())
+    }
+}
+
+/// Recipe dependency: `dep` or `dep(arg1, arg2)`
+#[derive(Debug, Clone)]
+pub struct RecipeDep {
+    /// name of the recipe to execute
+    pub name: String,
+    /// the dependency as it is written in the script
+    pub call: String,
+    /// expressions that make the dependency arguments. `None` - the dependency gets
+    /// the same free arguments as the recipe that depends on it
+    pub(crate) args: Option<Vec<Op>>,
+}
+
+impl fmt::Display for RecipeDep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.call)
+    }
+}
+
 /// Returns true if a value `flags` has a `flag` on
 pub fn is_flag_on(flags: u32, flag: u32) -> bool {
     flags & flag == flag
@@ -36,6 +80,7 @@ pub fn is_flag_on(flags: u32, flag: u32) -> bool {
 #[derive(Debug, Clone)]
 pub enum Op {
     /// Comment line (ignored) - comment text: starts with `#` or `//`
+    #[allow(dead_code)]
     Comment(String),
     /// Documentation comment - comment text: starts with `##`. Used as a recipe description
     /// when it is right before the recipe, ignored in other cases
@@ -50,9 +95,16 @@ pub enum Op {
     /// List of features which enable a following block of code
     ///
     /// * passed - whether all mentioned features are on (i.e., the block must be executed or
-    /// ignored)
+    ///   ignored)
     /// * string representation of a condition to enable the following code block
     Feature(bool, String),
+    /// Recipe attribute. It modifies how the following recipe is executed
+    ///
+    /// * attribute name in lower case
+    /// * attribute values
+    ///
+    /// Example: `#[outputs("target/app")]`
+    Attr(String, Vec<String>),
     /// Execute a function
     ///
     /// * function name
@@ -75,7 +127,7 @@ pub enum Op {
     /// Assign the first truthy value from the list of values
     ///
     /// * check - if it is true, the new value is calculated and assigned only if the current
-    /// variable value is undefined or a falsy one
+    ///   variable value is undefined or a falsy one
     /// * variable name
     /// * list of values
     ///
@@ -99,21 +151,34 @@ pub enum Op {
     Break,
     /// CONTINUE statement
     Continue,
-    /// RETURN statement
-    Return,
+    /// RETURN statement - the value to return from a function (empty if there is no value)
+    Return(Vec<Op>),
     /// WHILE statement - the loop enter condition
     While(Vec<Op>),
     /// FOR statement - range of for values
     For(String, Seq),
+    /// TRY statement - starts a block which errors are caught
+    Try,
+    /// CATCH statement - names of variables to keep the error message and exit code
+    Catch(Vec<String>),
+    /// FINALLY statement
+    Finally,
+    /// User-defined function declaration. The function body ends with END
+    ///
+    /// * function name
+    /// * list of argument names
+    ///
+    /// Example: `fn name(arg1, arg2)`
+    Function(String, Vec<String>),
     /// A recipe declaration
     ///
     /// * name
     /// * flags (e.g., "echo off" or "ignore shell errors")
-    /// * list of local recipe variable names
+    /// * list of local recipe variables
     /// * list of recipes this one depends on (they are executed before this recipe)
     ///
-    /// Example: `recipe-name loc_var1 +loc_var2: dependency1 dependency2
-    Recipe(String, u32, Vec<String>, Vec<String>),
+    /// Example: `recipe-name loc_var1 loc_var2="default" +loc_var3: dependency1 dependency2(${loc_var1})
+    Recipe(String, u32, Vec<RecipeArg>, Vec<RecipeDep>),
     /// Execute external command using the current shell
     ///
     /// * execution flags (e.g., "echo off" or "ignore shell errors")
@@ -123,14 +188,34 @@ pub enum Op {
     // here goes a list of basic building blocks of any expression
     /// Integer value(i64)
     Int(i64),
+    /// Boolean value: `true` or `false`
+    Bool(bool),
     /// String value
     Str(String),
     /// Variable name
     Var(String),
     /// result of external execution with shell
     Exec(String),
+    /// List literal: a value per list item: `[1, "a", $b]`
+    List(Vec<Op>),
+    /// Map literal: a key and a value per map entry: `{name: "a", "other key": $b}`
+    Map(Vec<(String, Op)>),
+    /// Item of a list or a map: the value followed by one or more indices: `$m["key"][0]`
+    Index(Vec<Op>),
     /// Logical negation of a value
     Not(Vec<Op>),
+    /// Arithmetic negation of a value: `-$a`
+    Neg(Vec<Op>),
+    /// Arithmetic operation
+    ///
+    /// * operator (+, -, *, /, %)
+    /// * list of values (should be 2 of them)
+    Arith(String, Vec<Op>),
+    /// String concatenation of all values: `$a .. "-" .. $b`
+    Concat(Vec<Op>),
+    /// A list of values joined with logical OR. The result of the expression is a logical value
+    /// 0 or 1 (1 - if any value is truthy one). Used only for a condition in parentheses
+    OrExpr(Vec<Op>),
     /// change working directory: flags, directory
     Cd(u32, String),
     /// PAUSE statement
@@ -149,6 +234,39 @@ fn str_to_flags(s: &str) -> u32 {
     flags
 }
 
+/// Parses a single recipe argument declaration: `name`, `name="default"`, or `+name`
+fn build_recipe_arg(p: Pairs<Rule>) -> RecipeArg {
+    let mut arg = RecipeArg { name: String::new(), default: None, list: false };
+    for s in p {
+        match s.as_rule() {
+            Rule::sec_arg_name => {
+                let name = s.as_str();
+                arg.list = name.starts_with('+');
+                arg.name = name.trim_start_matches('+').to_string();
+            }
+            Rule::sec_arg_default => arg.default = Some(strip_quotes(s.as_str()).to_string()),
+            _ => {}
+        }
+    }
+    arg
+}
+
+/// Parses a single recipe dependency: `dep` or `dep(arg1, arg2)`
+fn build_recipe_dep(p: Pair<Rule>) -> Result<RecipeDep, HakuError> {
+    let call = p.as_str().trim().to_string();
+    // `dep()` means that the dependency is called without arguments
+    let args = if call.ends_with(')') { Some(Vec::new()) } else { None };
+    let mut dep = RecipeDep { name: String::new(), call, args };
+    for s in p.into_inner() {
+        match s.as_rule() {
+            Rule::ident => dep.name = s.as_str().to_string(),
+            Rule::arglist => dep.args = Some(build_arglist(s.into_inner())?),
+            _ => {}
+        }
+    }
+    Ok(dep)
+}
+
 /// Parses a script line that describes a recipe
 pub fn build_recipe(p: Pairs<Rule>) -> Result<Op, HakuError> {
     let mut flags: u32 = 0;
@@ -164,20 +282,23 @@ pub fn build_recipe(p: Pairs<Rule>) -> Result<Op, HakuError> {
             Rule::sec_args => {
                 let inner = s.into_inner();
                 for s_in in inner {
-                    vars.push(s_in.as_str().to_string());
+                    vars.push(build_recipe_arg(s_in.into_inner()));
                 }
                 if !vars.is_empty() {
                     for v in &vars[..vars.len() - 1] {
-                        if v.starts_with('+') {
+                        if v.list {
                             return Err(HakuError::RecipeListArgError(pstr));
                         }
                     }
                 }
+                if vars.iter().any(|v| v.list && v.default.is_some()) {
+                    return Err(HakuError::RecipeListArgError(pstr));
+                }
             }
             Rule::sec_deps => {
                 let inner = s.into_inner();
                 for s_in in inner {
-                    deps.push(s_in.as_str().to_string());
+                    deps.push(build_recipe_dep(s_in)?);
                 }
             }
             _ => { /* skip all other parts like sec_sep */ }
@@ -187,6 +308,28 @@ pub fn build_recipe(p: Pairs<Rule>) -> Result<Op, HakuError> {
     Ok(Op::Recipe(name, flags, vars, deps))
 }
 
+/// Parses a list of recipe attributes: `#[sources("src/*.rs"), outputs("app")]`.
+/// Every attribute becomes a separate operation
+pub fn build_attrs(p: Pairs<Rule>) -> Result<Vec<Op>, HakuError> {
+    let mut attrs = Vec::new();
+    for attr in p {
+        if attr.as_rule() != Rule::attr {
+            continue;
+        }
+        let mut name = String::new();
+        let mut vals = Vec::new();
+        for s in attr.into_inner() {
+            match s.as_rule() {
+                Rule::attr_name => name = s.as_str().to_lowercase(),
+                Rule::attr_arg => vals.push(strip_quotes(s.as_str()).to_string()),
+                _ => {}
+            }
+        }
+        attrs.push(Op::Attr(name, vals));
+    }
+    Ok(attrs)
+}
+
 /// Parses a script line with cd statement
 pub fn build_cd(p: Pairs<Rule>) -> Result<Op, HakuError> {
     let mut flags: u32 = 0;
@@ -277,9 +420,10 @@ pub fn strip_var_deco(s: &str) -> &str {
 /// * ident1 ident2
 /// * `\`dir *.txt\``
 /// * `${var-name}` or `$var-name`
-fn build_seq(p: Pairs<Rule>) -> Result<Seq, HakuError> {
+/// * `[1, "a"]`, `{a: 1}`, or `$var-name["key"]`
+fn build_seq(mut p: Pairs<Rule>) -> Result<Seq, HakuError> {
     let text = p.as_str().to_owned();
-    for pair in p {
+    if let Some(pair) = p.next() {
         match pair.as_rule() {
             Rule::squoted | Rule::dquoted => return Ok(Seq::Str(strip_quotes(pair.as_str()).to_string())),
             Rule::exec => return Ok(Seq::Exec(strip_quotes(pair.as_str()).to_string())),
@@ -354,6 +498,7 @@ fn build_seq(p: Pairs<Rule>) -> Result<Seq, HakuError> {
                 }
                 return Ok(Seq::Var(var_name));
             }
+            Rule::list_lit | Rule::map_lit | Rule::indexed => return Ok(Seq::Value(Box::new(build_value(pair)?))),
             _ => unimplemented!(),
         }
     }
@@ -384,7 +529,7 @@ fn build_arg_value(p: Pair<Rule>) -> Result<Op, HakuError> {
         }
         Rule::exec => return Ok(Op::Exec(strip_quotes(p.as_str()).to_string())),
         Rule::string => {
-            for in_p in p.into_inner() {
+            if let Some(in_p) = p.into_inner().next() {
                 match in_p.as_rule() {
                     Rule::squoted | Rule::dquoted => return Ok(Op::Str(strip_quotes(in_p.as_str()).to_string())),
                     _ => unimplemented!(),
@@ -408,20 +553,6 @@ fn build_arg(p: Pairs<Rule>) -> Result<Op, HakuError> {
     for pair in p {
         match pair.as_rule() {
             Rule::not_op => neg = !neg,
-            Rule::arg => {
-                let val = pair.as_str().to_string();
-                if let Some(pp) = pair.into_inner().next() {
-                    let op = build_arg_value(pp);
-                    if neg {
-                        let op = op?;
-                        return Ok(Op::Not(vec![op]));
-                    } else {
-                        return op;
-                    }
-                } else {
-                    return Err(HakuError::ParseError(val, String::new()));
-                }
-            }
             _ => {
                 let op = build_arg_value(pair);
                 if neg {
@@ -436,12 +567,155 @@ fn build_arg(p: Pairs<Rule>) -> Result<Op, HakuError> {
     unimplemented!()
 }
 
+/// Parses a condition that is used as a single value: a condition in parentheses,
+/// a list item, a map value, or an index
+fn build_cond_value(p: Pair<Rule>) -> Result<Op, HakuError> {
+    let mut v = build_condition(p.into_inner())?;
+    if v.len() == 1 {
+        return Ok(v.pop().unwrap_or_else(|| unreachable!()));
+    }
+    Ok(Op::OrExpr(v))
+}
+
+/// Parses a list literal: `[1, "a", $b]`
+fn build_list(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    let mut items = Vec::new();
+    for pair in p {
+        match pair.as_rule() {
+            Rule::cond => items.push(build_cond_value(pair)?),
+            _ => unimplemented!(),
+        }
+    }
+    Ok(Op::List(items))
+}
+
+/// Parses a map literal: `{name: "a", "other key": $b}`
+fn build_map(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    let mut entries = Vec::new();
+    for entry in p {
+        let mut key = String::new();
+        for pair in entry.into_inner() {
+            match pair.as_rule() {
+                Rule::map_key => key = strip_quotes(pair.as_str()).to_string(),
+                Rule::cond => entries.push((key.clone(), build_cond_value(pair)?)),
+                _ => unimplemented!(),
+            }
+        }
+    }
+    Ok(Op::Map(entries))
+}
+
+/// Parses a value followed by indices: `$m["key"][0]`
+fn build_indexed(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    let mut v = Vec::new();
+    for pair in p {
+        match pair.as_rule() {
+            Rule::index => {
+                for idx in pair.into_inner() {
+                    v.push(build_cond_value(idx)?);
+                }
+            }
+            _ => v.push(build_value(pair)?),
+        }
+    }
+    Ok(Op::Index(v))
+}
+
+/// Parses a value that can be a condition in parentheses: `($a > 2 || $b)`, a list or
+/// a map literal, or an indexed value
+fn build_value(p: Pair<Rule>) -> Result<Op, HakuError> {
+    match p.as_rule() {
+        Rule::paren => {
+            for pair in p.into_inner() {
+                if let Rule::cond = pair.as_rule() {
+                    return build_cond_value(pair);
+                }
+            }
+            unreachable!()
+        }
+        Rule::bool_lit => Ok(Op::Bool(p.as_str() == "true")),
+        Rule::list_lit => build_list(p.into_inner()),
+        Rule::map_lit => build_map(p.into_inner()),
+        Rule::indexed => build_indexed(p.into_inner()),
+        _ => build_arg_value(p),
+    }
+}
+
+/// Parses a single value with optional unary operators: `-$a`, `!$a`, `not -(2 + 3)`
+fn build_unary(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    let mut prefix = Vec::new();
+    for pair in p {
+        match pair.as_rule() {
+            Rule::not_op | Rule::neg_op => prefix.push(pair.as_rule()),
+            Rule::value => {
+                let mut op = match pair.into_inner().next() {
+                    Some(v) => build_value(v)?,
+                    None => unreachable!(),
+                };
+                for rule in prefix.iter().rev() {
+                    op = if *rule == Rule::not_op { Op::Not(vec![op]) } else { Op::Neg(vec![op]) };
+                }
+                return Ok(op);
+            }
+            _ => unimplemented!(),
+        }
+    }
+    unreachable!()
+}
+
+/// Parses a chain of values joined with binary operators of the same precedence. The
+/// operators are left-associative: `1 - 2 - 3` is `(1 - 2) - 3`
+fn build_binary(p: Pairs<Rule>, build: fn(Pairs<Rule>) -> Result<Op, HakuError>) -> Result<Op, HakuError> {
+    let mut res: Option<Op> = None;
+    let mut op_str = String::new();
+    for pair in p {
+        match pair.as_rule() {
+            Rule::mul_op | Rule::add_op => op_str = pair.as_str().to_string(),
+            _ => {
+                let val = build(pair.into_inner())?;
+                res = Some(match res {
+                    None => val,
+                    Some(lhs) => Op::Arith(op_str.clone(), vec![lhs, val]),
+                });
+            }
+        }
+    }
+    Ok(res.unwrap_or_else(|| unreachable!()))
+}
+
+/// Parses multiplication, division, and modulo: `$a * 2 / 3`
+fn build_product(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    build_binary(p, build_unary)
+}
+
+/// Parses addition and subtraction: `$a + 2 * 3 - 1`
+fn build_sum(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    build_binary(p, build_product)
+}
+
+/// Parses a function argument or a side of comparison: an arithmetic expression or
+/// a string concatenation of a few arithmetic expressions: `"v" .. $major + 1 .. ".0"`
+fn build_arg_expr(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    let mut v = Vec::new();
+    for pair in p {
+        match pair.as_rule() {
+            Rule::sum => v.push(build_sum(pair.into_inner())?),
+            Rule::concat_op => {}
+            _ => unimplemented!(),
+        }
+    }
+    if v.len() == 1 {
+        return Ok(v.pop().unwrap_or_else(|| unreachable!()));
+    }
+    Ok(Op::Concat(v))
+}
+
 /// Parses a list of function or expression values
 fn build_arglist(p: Pairs<Rule>) -> Result<Vec<Op>, HakuError> {
     let mut vec: Vec<Op> = Vec::new();
     for pair in p {
         match pair.as_rule() {
-            Rule::arg => vec.push(build_arg(pair.into_inner())?),
+            Rule::arg => vec.push(build_arg_expr(pair.into_inner())?),
             _ => unimplemented!(),
         }
     }
@@ -472,7 +746,7 @@ fn build_s_expr(p: Pairs<Rule>) -> Result<Op, HakuError> {
     let mut cmp = String::new();
     for pair in p {
         match pair.as_rule() {
-            Rule::arg => v.push(build_arg(pair.into_inner())?),
+            Rule::arg => v.push(build_arg_expr(pair.into_inner())?),
             Rule::cmp_op => cmp = pair.as_str().to_string(),
             _ => {
                 println!("{:?}", pair);
@@ -635,6 +909,47 @@ pub fn build_while(p: Pairs<Rule>) -> Result<Op, HakuError> {
     unreachable!()
 }
 
+/// Parses RETURN statement: `return` or `return $value`
+pub fn build_return(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    for pair in p {
+        if let Rule::cond = pair.as_rule() {
+            return Ok(Op::Return(build_condition(pair.into_inner())?));
+        }
+    }
+    Ok(Op::Return(Vec::new()))
+}
+
+/// Parses CATCH statement: `catch`, `catch err`, or `catch err, code`
+pub fn build_catch(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    let mut names = Vec::new();
+    for pair in p {
+        if let Rule::catch_vars = pair.as_rule() {
+            for name in pair.into_inner() {
+                names.push(name.as_str().to_string());
+            }
+        }
+    }
+    Ok(Op::Catch(names))
+}
+
+/// Parses a function declaration: `fn name(arg1, arg2)`
+pub fn build_fn(p: Pairs<Rule>) -> Result<Op, HakuError> {
+    let mut name = String::new();
+    let mut params = Vec::new();
+    for pair in p {
+        match pair.as_rule() {
+            Rule::ident => name = pair.as_str().to_string(),
+            Rule::fn_params => {
+                for prm in pair.into_inner() {
+                    params.push(prm.as_str().to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(Op::Function(name, params))
+}
+
 pub(crate) fn s_to_i64(s: &str) -> Result<i64, ()> {
     if !s.starts_with("0x") && !s.starts_with("0X") {
         if let Ok(i) = s.parse::<i64>() {
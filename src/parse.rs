@@ -6,10 +6,12 @@ use pest::Parser;
 use crate::errors::HakuError;
 use crate::feature::process_feature;
 use crate::ops::{
-    build_assign, build_cd, build_def_assign, build_either_assign, build_either_def_assign, build_elseif, build_error,
-    build_for, build_func, build_if, build_include, build_recipe, build_shell_cmd, build_while, Op,
+    build_assign, build_attrs, build_catch, build_cd, build_def_assign, build_either_assign, build_either_def_assign,
+    build_elseif, build_error, build_fn, build_for, build_func, build_if, build_include, build_recipe, build_return,
+    build_shell_cmd, build_while, Op, RecipeArg, RecipeDep,
 };
-use crate::vm::RunOpts;
+use crate::timeout::parse_duration;
+use crate::vm::{OutputMode, Retry, RunOpts};
 
 #[derive(Parser)]
 #[grammar = "haku.pest"]
@@ -24,8 +26,16 @@ pub struct DisabledRecipe {
     pub desc: String,
     /// list of features when the recipe is enabled
     pub feat: String,
+    /// recipe-wide flags (i.e., echo off, skip errors)
+    pub flags: u32,
+    /// recipe arguments
+    pub vars: Vec<RecipeArg>,
+    /// a list of recipes this one depends on
+    pub depends: Vec<RecipeDep>,
     /// the first line number
     pub line: usize,
+    /// the number of the script the recipe is declared in (see `Engine::file_name`)
+    pub file: usize,
 }
 
 /// A single operation description
@@ -38,6 +48,7 @@ pub(crate) struct OpItem {
 }
 
 /// A single script description
+#[derive(Clone)]
 pub(crate) struct HakuFile {
     /// list of lines that can be executed (all comment and disabled code are removed)
     pub(crate) ops: Vec<OpItem>,
@@ -110,7 +121,7 @@ impl HakuFile {
 
         let pairs = match res {
             Err(e) => {
-                let msg = format!("'{}': {}", line, e.to_string());
+                let msg = format!("'{}': {}", line, e);
                 return Err(HakuError::ParseError(msg, HakuError::error_extra("", line, idx)));
             }
             Ok(p) => p,
@@ -179,12 +190,41 @@ impl HakuFile {
                 Rule::else_stmt => {
                     self.ops.push(OpItem { op: Op::Else, line: idx });
                 }
+                Rule::try_stmt => {
+                    self.ops.push(OpItem { op: Op::Try, line: idx });
+                }
+                Rule::catch_stmt => {
+                    self.ops.push(OpItem { op: build_catch(pair.into_inner())?, line: idx });
+                }
+                Rule::finally_stmt => {
+                    self.ops.push(OpItem { op: Op::Finally, line: idx });
+                }
                 Rule::return_stmt => {
-                    self.ops.push(OpItem { op: Op::Return, line: idx });
+                    self.ops.push(OpItem { op: build_return(pair.into_inner())?, line: idx });
+                }
+                Rule::fn_stmt => {
+                    self.ops.push(OpItem { op: build_fn(pair.into_inner())?, line: idx });
                 }
                 Rule::recipe => {
                     self.ops.push(OpItem { op: build_recipe(pair.into_inner())?, line: idx });
                 }
+                Rule::attr_list => {
+                    for op in build_attrs(pair.into_inner())? {
+                        if let Op::Attr(ref name, ref vals) = op {
+                            let valid = match name.as_str() {
+                                "timeout" => vals.first().and_then(|v| parse_duration(v)).is_some(),
+                                "retry" => Retry::from_attr(vals).is_some(),
+                                "output" => vals.len() == 1 && OutputMode::from_name(&vals[0]).is_some(),
+                                _ => true,
+                            };
+                            if !valid {
+                                let msg = format!("'{}': invalid {} value", line, name);
+                                return Err(HakuError::ParseError(msg, HakuError::error_extra("", line, idx)));
+                            }
+                        }
+                        self.ops.push(OpItem { op, line: idx });
+                    }
+                }
                 Rule::feature_list => {
                     let txt = pair.as_str();
                     let pass = match process_feature(pair.into_inner(), opts, &mut feat_list) {
@@ -238,7 +278,7 @@ impl HakuFile {
                 hk.orig_lines.push(l.trim_end().to_string());
                 let l = l.trim();
                 full_line += l;
-                if full_line == "" {
+                if full_line.is_empty() {
                     continue;
                 }
                 if full_line.ends_with('\\') {
@@ -250,7 +290,7 @@ impl HakuFile {
                 return Err(HakuError::FileReadFailure(path.to_string()));
             }
 
-            if full_line != "" {
+            if !full_line.is_empty() {
                 hk.process_line(&full_line, idx, opts)?;
                 full_line.clear();
             }
@@ -270,12 +310,12 @@ impl HakuFile {
             hk.orig_lines.push(l.trim_end().to_string());
             let l = l.trim();
             full_line += l;
-            if full_line.ends_with('\\') || full_line == "" {
+            if full_line.ends_with('\\') || full_line.is_empty() {
                 idx += 1;
                 continue;
             }
 
-            if full_line != "" {
+            if !full_line.is_empty() {
                 hk.process_line(&full_line, idx, opts)?;
                 full_line.clear();
             }
@@ -304,6 +344,13 @@ impl HakuFile {
                         ds.f_list.push(o);
                     }
                 }
+                Op::Attr(_, _) => {
+                    if skip == Skip::Recipe {
+                        ds.next_f_list.push(o);
+                    } else {
+                        ds.f_list.push(o);
+                    }
+                }
                 Op::Feature(b, ref s) => {
                     if skip == Skip::Recipe {
                         ds.next_pass &= b;
@@ -313,17 +360,31 @@ impl HakuFile {
                         ds.fstr += s;
                     }
                 }
-                Op::Recipe(ref name, _, _, _) => {
+                Op::Recipe(ref name, flags, ref vars, ref deps) => {
                     if skip == Skip::Recipe && !ds.next_pass {
                         self.disabled.push(DisabledRecipe {
                             name: name.to_string(),
                             desc: ds.next_desc.clone(),
                             feat: ds.next_fstr.clone(),
+                            flags,
+                            vars: vars.clone(),
+                            depends: deps.clone(),
                             line: o.line,
+                            file: 0,
                         });
                     } else if skip != Skip::None || ds.pass {
+                        // keep the features of an enabled recipe, so they can be listed
+                        let fstr = if skip == Skip::Recipe { &ds.next_fstr } else { &ds.fstr };
+                        if !fstr.is_empty() {
+                            op_list.push(OpItem { op: Op::Feature(true, fstr.clone()), line: o.line });
+                        }
                         skip = Skip::None;
                         op_list.append(&mut ds.f_list);
+                        for item in ds.next_f_list.iter() {
+                            if let Op::Attr(_, _) = item.op {
+                                op_list.push(item.clone());
+                            }
+                        }
                         if !ds.next_desc.is_empty() {
                             op_list.push(OpItem { op: Op::DocComment(ds.next_desc.clone()), line: o.line });
                         }
@@ -333,13 +394,17 @@ impl HakuFile {
                             name: name.to_string(),
                             desc: ds.desc.clone(),
                             feat: ds.fstr.clone(),
+                            flags,
+                            vars: vars.clone(),
+                            depends: deps.clone(),
                             line: o.line,
+                            file: 0,
                         });
                         skip = Skip::Recipe;
                     }
                     ds.reset();
                 }
-                Op::If(_) | Op::While(_) | Op::For(_, _) => {
+                Op::If(_) | Op::While(_) | Op::For(_, _) | Op::Function(_, _) | Op::Try => {
                     if skip != Skip::None {
                         nesting += 1;
                     } else if ds.pass {
@@ -365,6 +430,12 @@ impl HakuFile {
                 }
                 _ => {
                     if skip == Skip::None && ds.pass {
+                        // keep attributes of a line, e.g. command time limit
+                        for item in ds.f_list.iter() {
+                            if let Op::Attr(_, _) = item.op {
+                                op_list.push(item.clone());
+                            }
+                        }
                         op_list.push(o);
                     }
                     ds.reset();
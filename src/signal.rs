@@ -0,0 +1,172 @@
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+
+/// The number of signals received since the last reset. It is never decreased by a check,
+/// so every engine that runs in parallel with others notices every signal
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+/// The number of the last received signal, `0` - no signal
+static LAST: AtomicI32 = AtomicI32::new(0);
+/// The number of running scripts that handle signals
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// The number of scripts waiting for user input: while any waits, a signal kills `haku`
+static PAUSED: AtomicUsize = AtomicUsize::new(0);
+/// Child processes that are running at this moment: process ID and whether the process has
+/// its own process group
+static CHILDREN: Mutex<Vec<(u32, bool)>> = Mutex::new(Vec::new());
+static INSTALL: Once = Once::new();
+
+/// Installs SIGINT, SIGTERM, and SIGHUP handlers. The handlers are installed once, next
+/// calls do nothing. While a script runs (see `catch`), the signals do not kill `haku`:
+/// the engine forwards a signal to running external commands, waits for them, and stops
+/// with an error. Outside a run, or while a script waits for user input, a signal does
+/// what it does by default: terminates the process.
+///
+/// On other platforms the function does nothing
+fn install() {
+    INSTALL.call_once(|| {
+        #[cfg(unix)]
+        {
+            use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM};
+            use signal_hook::iterator::Signals;
+            use signal_hook::low_level::emulate_default_handler;
+            use std::thread;
+
+            let mut signals = match Signals::new([SIGINT, SIGTERM, SIGHUP]) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Failed to install signal handler: {}", e);
+                    return;
+                }
+            };
+            thread::spawn(move || {
+                for sig in signals.forever() {
+                    if catching() {
+                        record(sig);
+                    } else {
+                        let _ = emulate_default_handler(sig);
+                    }
+                }
+            });
+        }
+    });
+}
+
+/// Returns `true` if a received signal must stop the script instead of killing `haku`
+#[cfg(unix)]
+fn catching() -> bool {
+    ACTIVE.load(Ordering::SeqCst) != 0 && PAUSED.load(Ordering::SeqCst) == 0
+}
+
+/// Remembers a received signal for running scripts and forwards it to running commands
+#[cfg(unix)]
+fn record(sig: i32) {
+    LAST.store(sig, Ordering::SeqCst);
+    COUNT.fetch_add(1, Ordering::SeqCst);
+    forward(sig);
+}
+
+/// Handles a signal that has killed a child process in the foreground process group of the
+/// terminal: a user's Ctrl-C reaches only that group, so `haku` acts as if it received the
+/// signal as well. The signal is recorded at once if a script handles signals, so the next
+/// check sees it. Otherwise the signal is raised and does what it does by default
+#[cfg(unix)]
+pub(crate) fn resend(sig: i32) {
+    if INSTALL.is_completed() && catching() {
+        record(sig);
+    } else {
+        unsafe {
+            libc::raise(sig);
+        }
+    }
+}
+
+/// Sends a signal to all running child processes. A child that shares the process group
+/// with `haku` gets SIGINT from the terminal directly, so SIGINT is sent only to children
+/// that have their own process groups
+#[cfg(unix)]
+fn forward(sig: i32) {
+    let children = match CHILDREN.lock() {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+    for (pid, own_group) in children.iter() {
+        let pid = *pid as libc::pid_t;
+        unsafe {
+            if *own_group {
+                libc::kill(-pid, sig);
+            } else if sig != libc::SIGINT {
+                libc::kill(pid, sig);
+            }
+        }
+    }
+}
+
+/// Returns the number of the last signal if more than `seen` signals have been received
+/// since the last reset, and updates `seen`
+pub(crate) fn check(seen: &mut usize) -> Option<i32> {
+    let cnt = COUNT.load(Ordering::SeqCst);
+    if cnt <= *seen {
+        return None;
+    }
+    *seen = cnt;
+    Some(LAST.load(Ordering::SeqCst))
+}
+
+/// Forgets signals received before a new run starts
+pub(crate) fn reset() {
+    COUNT.store(0, Ordering::SeqCst);
+    LAST.store(0, Ordering::SeqCst);
+}
+
+/// Handles signals while the guard is alive (see `install`)
+pub(crate) struct RunGuard;
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Installs signal handlers if needed and makes them stop a script instead of killing
+/// `haku` until the guard is dropped
+pub(crate) fn catch() -> RunGuard {
+    install();
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+    RunGuard
+}
+
+/// Restores the default signal action while the guard is alive
+pub(crate) struct PauseGuard;
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        PAUSED.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Makes a signal kill `haku` while a script waits for user input
+pub(crate) fn pause() -> PauseGuard {
+    PAUSED.fetch_add(1, Ordering::SeqCst);
+    PauseGuard
+}
+
+/// Keeps a child process in the list of running ones while the guard is alive
+pub(crate) struct ChildGuard {
+    pid: u32,
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if let Ok(mut children) = CHILDREN.lock() {
+            children.retain(|(pid, _)| *pid != self.pid);
+        }
+    }
+}
+
+/// Adds a child process to the list of processes that receive forwarded signals
+pub(crate) fn track(pid: u32, own_group: bool) -> ChildGuard {
+    if let Ok(mut children) = CHILDREN.lock() {
+        children.push((pid, own_group));
+    }
+    ChildGuard { pid }
+}
@@ -0,0 +1,169 @@
+use std::env;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::Instant;
+
+use crate::timeout::wait_child;
+
+/// ANSI escape sequence to start painting text in red
+const COLOR_ERR: &str = "\x1b[31m";
+/// ANSI escape sequence to reset text color
+const COLOR_RESET: &str = "\x1b[0m";
+
+/// How the output of shell commands is displayed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputMode {
+    /// commands write directly to the terminal
+    Plain,
+    /// every line is prefixed with the recipe name
+    Prefix,
+    /// every line is prefixed with the recipe name and the time elapsed since haku started
+    Time,
+}
+
+impl OutputMode {
+    /// Converts a mode name to a mode. Returns `None` for unknown names
+    pub fn from_name(name: &str) -> Option<OutputMode> {
+        match name.to_lowercase().as_str() {
+            "plain" | "raw" => Some(OutputMode::Plain),
+            "prefix" => Some(OutputMode::Prefix),
+            "time" | "timestamp" => Some(OutputMode::Time),
+            _ => None,
+        }
+    }
+}
+
+/// Builds a prefix for every output line: `[recipe]` or `[recipe +1.234s]`
+pub(crate) fn line_prefix(mode: OutputMode, recipe: &str, started: Instant) -> String {
+    match mode {
+        OutputMode::Plain => String::new(),
+        OutputMode::Prefix => format!("[{}] ", recipe),
+        OutputMode::Time => format!("[{} +{:.3}s] ", recipe, started.elapsed().as_secs_f64()),
+    }
+}
+
+/// Returns `true` if error output should be painted: it is a terminal and a user has not
+/// disabled colors with `NO_COLOR` environment variable
+fn use_color() -> bool {
+    io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none()
+}
+
+/// Reads lines until the source is closed and calls `f` for every line without its line
+/// ending. Invalid UTF-8 sequences are replaced
+fn read_lines<R: Read, F: FnMut(&str)>(src: R, mut f: F) {
+    let mut rdr = BufReader::new(src);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match rdr.read_until(b'\n', &mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let line = String::from_utf8_lossy(&buf);
+        f(line.trim_end_matches(&['\r', '\n'][..]));
+    }
+}
+
+/// Builds a line to print: the prefix and the line text painted red if `color` is `true`
+fn format_line(prefix: &str, line: &str, color: bool) -> String {
+    if color {
+        format!("{}{}{}{}", prefix, COLOR_ERR, line, COLOR_RESET)
+    } else {
+        format!("{}{}", prefix, line)
+    }
+}
+
+/// Reads a child process output line by line and prints every line with a prefix
+fn pipe_lines<R: Read>(src: R, mode: OutputMode, recipe: &str, started: Instant, is_err: bool) {
+    let color = is_err && use_color();
+    read_lines(src, |line| {
+        let line = format_line(&line_prefix(mode, recipe, started), line, color);
+        // write errors are ignored: there is no better place to report them
+        if is_err {
+            let _ = writeln!(io::stderr().lock(), "{}", line);
+        } else {
+            let _ = writeln!(io::stdout().lock(), "{}", line);
+        }
+    });
+}
+
+/// Runs a command, captures its standard and error output, and prints them line by line
+/// as soon as a line is available. Every line gets a prefix built by `line_prefix`.
+///
+/// Returns `None` if the command has been killed because `deadline` came
+pub(crate) fn run_streamed(
+    cmd: &mut Command,
+    mode: OutputMode,
+    recipe: &str,
+    started: Instant,
+    deadline: Option<Instant>,
+) -> io::Result<Option<ExitStatus>> {
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let out_name = recipe.to_string();
+    let err_name = recipe.to_string();
+    let out_thread = stdout.map(|s| thread::spawn(move || pipe_lines(s, mode, &out_name, started, false)));
+    let err_thread = stderr.map(|s| thread::spawn(move || pipe_lines(s, mode, &err_name, started, true)));
+    let status = wait_child(&mut child, deadline)?;
+    for th in out_thread.into_iter().chain(err_thread) {
+        let _ = th.join();
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod stream_test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(OutputMode::from_name("Prefix"), Some(OutputMode::Prefix));
+        assert_eq!(OutputMode::from_name("timestamp"), Some(OutputMode::Time));
+        assert_eq!(OutputMode::from_name("raw"), Some(OutputMode::Plain));
+        assert_eq!(OutputMode::from_name("bogus"), None);
+
+        let started = Instant::now();
+        assert_eq!(line_prefix(OutputMode::Plain, "build", started), "");
+        assert_eq!(line_prefix(OutputMode::Prefix, "build", started), "[build] ");
+        let started = Instant::now() - Duration::from_millis(1500);
+        let p = line_prefix(OutputMode::Time, "build", started);
+        assert!(p.starts_with("[build +1.") && p.ends_with("s] "), "{}", p);
+        // three digits after the decimal point
+        assert_eq!(p.len(), "[build +1.500s] ".len(), "{}", p);
+
+        assert_eq!(format_line("[a] ", "text", false), "[a] text");
+        assert_eq!(format_line("[a] ", "text", true), "[a] \x1b[31mtext\x1b[0m");
+    }
+
+    #[test]
+    fn lines() {
+        let mut v = Vec::new();
+        read_lines(&b"one\r\ntwo\n\nthr\xffee"[..], |l| v.push(l.to_string()));
+        assert_eq!(v, vec!["one", "two", "", "thr\u{fffd}ee"]);
+        let mut v = Vec::new();
+        read_lines(&b""[..], |l| v.push(l.to_string()));
+        assert!(v.is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn streamed() {
+        let started = Instant::now();
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("exit 3");
+        let st = run_streamed(&mut cmd, OutputMode::Prefix, "build", started, None).unwrap();
+        assert_eq!(st.and_then(|s| s.code()), Some(3));
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("exec sleep 5");
+        crate::timeout::new_group(&mut cmd);
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let st = run_streamed(&mut cmd, OutputMode::Time, "build", started, Some(deadline)).unwrap();
+        assert!(st.is_none());
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+}
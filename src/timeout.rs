@@ -0,0 +1,235 @@
+use std::io;
+use std::process::{Child, Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::signal;
+
+/// How often the engine checks if a child process with a time limit has finished
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Parses a time limit: a number with an optional suffix `ms`, `s`, `m`, or `h`. A number
+/// without suffix is the number of seconds. Examples: `500ms`, `30s`, `1.5m`, `2h`, `10`.
+///
+/// Returns `None` if the value is invalid or zero
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim().to_lowercase();
+    let (num, mult) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else if let Some(n) = s.strip_suffix('h') {
+        (n, 3600.0)
+    } else {
+        (s.as_str(), 1.0)
+    };
+    let val = num.trim().parse::<f64>().ok()?;
+    if !val.is_finite() || val <= 0.0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(val * mult))
+}
+
+/// Makes a command start in its own process group, so the command and all processes it
+/// spawns can be killed at once when the time is out.
+///
+/// If `haku` runs in a terminal, the new group also becomes the foreground process group of
+/// the terminal while the command runs (see `Terminal`): the command can read the terminal,
+/// and Ctrl-C reaches it
+pub(crate) fn new_group(cmd: &mut Command) {
+    #[cfg(unix)]
+    {
+        use std::io::IsTerminal;
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+        if io::stdin().is_terminal() {
+            // the child takes the terminal itself as well, so it does not depend on whether
+            // it starts reading the terminal before or after `haku` hands the terminal over
+            unsafe {
+                cmd.pre_exec(|| {
+                    let parent_group = libc::getpgid(libc::getppid());
+                    libc::setpgid(0, 0);
+                    if libc::tcgetpgrp(libc::STDIN_FILENO) == parent_group {
+                        set_foreground(libc::getpid());
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = cmd;
+}
+
+/// Makes a process group the foreground process group of the terminal. SIGTTOU is blocked
+/// for the time of the call, otherwise a process from a background group is stopped by it
+#[cfg(unix)]
+fn set_foreground(pgid: libc::pid_t) {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        let mut old: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &old, std::ptr::null_mut());
+    }
+}
+
+/// Keeps a child process group in the foreground of the terminal while the guard is alive,
+/// and gives the terminal back to `haku` when it is dropped
+struct Terminal;
+
+impl Terminal {
+    /// Hands the terminal over to the process group `pgid`. Returns `None` if the standard
+    /// input is not a terminal or another command has taken the terminal already (e.g., when
+    /// recipes run in parallel): the command stays in the background then
+    fn take(pgid: u32) -> Option<Terminal> {
+        #[cfg(unix)]
+        {
+            use std::io::IsTerminal;
+            if !io::stdin().is_terminal() {
+                return None;
+            }
+            let pgid = pgid as libc::pid_t;
+            let fg = unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) };
+            if fg != pgid && fg != unsafe { libc::getpgrp() } {
+                return None;
+            }
+            set_foreground(pgid);
+            Some(Terminal)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = pgid;
+            None
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        set_foreground(unsafe { libc::getpgrp() });
+    }
+}
+
+/// Puts a child process started by `new_group` into its own process group. The child does
+/// it itself, but `haku` may get to the child before that, so both do it, as shells do.
+/// Returns `true` if the child is the leader of its own process group
+fn own_group(child: &Child) -> bool {
+    #[cfg(unix)]
+    {
+        let pid = child.id() as libc::pid_t;
+        unsafe {
+            libc::setpgid(pid, pid);
+            libc::getpgid(pid) == pid
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = child;
+        false
+    }
+}
+
+/// Kills a child process. On Unix the entire process group of the child is killed if the
+/// child has its own group
+fn kill_group(child: &mut Child, own_group: bool) {
+    #[cfg(unix)]
+    {
+        if own_group {
+            // the child is the leader of its process group, so group ID is the child's PID
+            let pgid = child.id() as libc::pid_t;
+            unsafe {
+                libc::kill(-pgid, libc::SIGKILL);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = own_group;
+    let _ = child.kill();
+}
+
+/// Waits for a child process to finish. If `deadline` comes first, the child process is
+/// killed and the function returns `None`.
+///
+/// A child with a deadline must be started by `new_group`: then the whole process group is
+/// killed, and, in a terminal, the child runs in the foreground. A user's Ctrl-C reaches
+/// only the foreground child in this case, so `haku` handles it as if it received SIGINT
+/// itself. While the function waits, the child receives signals forwarded by `haku`
+pub(crate) fn wait_child(child: &mut Child, deadline: Option<Instant>) -> io::Result<Option<ExitStatus>> {
+    let deadline = match deadline {
+        None => {
+            let _guard = signal::track(child.id(), false);
+            return child.wait().map(Some);
+        }
+        Some(d) => d,
+    };
+    let own_group = own_group(child);
+    let _guard = signal::track(child.id(), own_group);
+    let terminal = if own_group { Terminal::take(child.id()) } else { None };
+    loop {
+        if let Some(st) = child.try_wait()? {
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::ExitStatusExt;
+                if terminal.is_some() && st.signal() == Some(libc::SIGINT) {
+                    signal::resend(libc::SIGINT);
+                }
+            }
+            return Ok(Some(st));
+        }
+        if Instant::now() >= deadline {
+            kill_group(child, own_group);
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod timeout_test {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5m"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration(" 10 "), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("0"), None);
+        assert_eq!(parse_duration("-5s"), None);
+        assert_eq!(parse_duration("fast"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn kill_process_group() {
+        // the command starts a process in the background and waits for it
+        let path = std::env::temp_dir().join(format!("haku-group-{}", std::process::id()));
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!("sleep 30 & echo $! > '{}'; wait", path.to_string_lossy()));
+        new_group(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
+        let st = wait_child(&mut child, Some(Instant::now() + Duration::from_millis(300))).unwrap();
+        assert!(st.is_none());
+        let pid: libc::pid_t = std::fs::read_to_string(&path).unwrap().trim().parse().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // a killed process can stay a zombie for a while until its new parent reaps it
+        let gone = || {
+            let zombie = std::fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|s| s.contains(") Z"));
+            zombie || unsafe { libc::kill(pid, 0) } != 0
+        };
+        let start = Instant::now();
+        while !gone() && start.elapsed() < Duration::from_secs(3) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert!(gone(), "background process {} is still running", pid);
+    }
+}
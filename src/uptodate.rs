@@ -0,0 +1,125 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use glob::glob;
+
+/// Returns `true` if a path contains any glob special characters
+fn is_pattern(s: &str) -> bool {
+    s.contains('*') || s.contains('?') || s.contains('[')
+}
+
+/// Expands a list of paths and glob patterns to a list of existing filesystem objects.
+/// Relative paths are resolved against `cwd`.
+///
+/// Returns `None` if any plain path(not a glob pattern) does not exist. If `strict` is `true`,
+/// a glob pattern that matches nothing is treated as a missing path as well.
+pub(crate) fn expand_paths(patterns: &[String], cwd: &Path, strict: bool) -> Option<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for patt in patterns.iter() {
+        let full = if Path::new(patt).is_absolute() { PathBuf::from(patt) } else { cwd.join(patt) };
+        if !is_pattern(patt) {
+            if !full.exists() {
+                return None;
+            }
+            files.push(full);
+            continue;
+        }
+        let entries = match glob(&full.to_string_lossy()) {
+            Ok(e) => e,
+            Err(_) => return None,
+        };
+        let before = files.len();
+        for p in entries.flatten() {
+            files.push(p);
+        }
+        if strict && files.len() == before {
+            return None;
+        }
+    }
+    Some(files)
+}
+
+/// Returns the time of the last modification of a filesystem object
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Checks whether recipe outputs are up to date: all outputs exist, and the oldest output
+/// is not older than the newest source. A recipe without outputs is never up to date.
+pub(crate) fn is_up_to_date(outputs: &[String], sources: &[String], cwd: &Path) -> bool {
+    if outputs.is_empty() {
+        return false;
+    }
+    let outs = match expand_paths(outputs, cwd, true) {
+        None => return false,
+        Some(v) => v,
+    };
+    let mut oldest: Option<SystemTime> = None;
+    for o in outs.iter() {
+        match mtime(o) {
+            None => return false,
+            Some(t) => {
+                if oldest.is_none_or(|old| t < old) {
+                    oldest = Some(t);
+                }
+            }
+        }
+    }
+    let oldest = match oldest {
+        None => return false,
+        Some(t) => t,
+    };
+
+    let srcs = match expand_paths(sources, cwd, false) {
+        None => return false,
+        Some(v) => v,
+    };
+    for s in srcs.iter() {
+        match mtime(s) {
+            None => return false,
+            Some(t) => {
+                if t > oldest {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod uptodate_test {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::time::Duration;
+
+    fn touch(path: &Path, age_secs: u64) {
+        let f = File::create(path).unwrap();
+        f.set_modified(SystemTime::now() - Duration::from_secs(age_secs)).unwrap();
+    }
+
+    #[test]
+    fn outputs_and_sources() {
+        let dir = env::temp_dir().join(format!("haku-uptodate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        touch(&dir.join("a.src"), 100);
+        touch(&dir.join("b.src"), 50);
+        touch(&dir.join("app.out"), 10);
+
+        let outs = vec!["app.out".to_string()];
+        let srcs = vec!["*.src".to_string()];
+        assert!(is_up_to_date(&outs, &srcs, &dir));
+        assert!(is_up_to_date(&outs, &[], &dir));
+        assert!(!is_up_to_date(&[], &srcs, &dir));
+        assert!(!is_up_to_date(&["missing.out".to_string()], &srcs, &dir));
+        assert!(!is_up_to_date(&["*.none".to_string()], &srcs, &dir));
+        assert!(!is_up_to_date(&outs, &["missing.src".to_string()], &dir));
+
+        touch(&dir.join("b.src"), 0);
+        assert!(!is_up_to_date(&outs, &srcs, &dir));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::From;
 use std::env;
-use std::usize;
+use std::fmt;
 
 use crate::output;
 
@@ -17,6 +17,8 @@ pub struct ExecResult {
     pub(crate) code: i32,
     /// process standard output
     pub(crate) stdout: String,
+    /// process error output
+    pub(crate) stderr: String,
 }
 
 /// Variable value
@@ -28,10 +30,14 @@ pub enum VarValue {
     Str(String),
     /// contains an integer value
     Int(i64),
-    /// contains a list of strings
-    List(Vec<String>),
+    /// contains a boolean value: `true` or `false`
+    Bool(bool),
+    /// contains a list of values of any type
+    List(Vec<VarValue>),
+    /// contains a map: string keys and values of any type. Keys are sorted
+    Map(BTreeMap<String, VarValue>),
     /// contains a result of external shell execution command
-    Exec(ExecResult),
+    Exec(Box<ExecResult>),
 }
 
 impl From<String> for VarValue {
@@ -69,27 +75,41 @@ impl From<bool> for VarValue {
     }
 }
 
-impl ToString for VarValue {
-    fn to_string(&self) -> String {
+impl fmt::Display for VarValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            VarValue::Undefined => String::new(),
-            VarValue::Str(s) => s.clone(),
-            VarValue::Int(i) => format!("{}", i),
+            VarValue::Undefined => Ok(()),
+            VarValue::Str(s) => write!(f, "{}", s),
+            VarValue::Int(i) => write!(f, "{}", i),
+            VarValue::Bool(b) => write!(f, "{}", b),
             VarValue::List(v) => {
-                let mut s = String::new();
+                let mut empty = true;
                 for it in v.iter() {
-                    if !s.is_empty() {
-                        s += "\n";
+                    if !empty {
+                        writeln!(f)?;
                     }
-                    s += it;
+                    let s = it.to_flat_string();
+                    write!(f, "{}", s)?;
+                    empty &= s.is_empty();
                 }
-                s
+                Ok(())
+            }
+            VarValue::Map(m) => {
+                let mut first = true;
+                for (k, v) in m.iter() {
+                    if !first {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}={}", k, v.to_flat_string())?;
+                    first = false;
+                }
+                Ok(())
             }
             VarValue::Exec(ex) => {
                 if ex.code == 0 {
-                    ex.stdout.to_string()
+                    write!(f, "{}", ex.stdout)
                 } else {
-                    String::new()
+                    Ok(())
                 }
             }
         }
@@ -113,13 +133,24 @@ impl VarValue {
             VarValue::Undefined => String::new(),
             VarValue::Str(s) => s.clone(),
             VarValue::Int(i) => format!("{}", i),
+            VarValue::Bool(b) => format!("{}", b),
             VarValue::List(v) => {
                 let mut s = String::new();
                 for it in v.iter() {
                     if !s.is_empty() {
                         s += " ";
                     }
-                    s += it;
+                    s += &it.to_flat_string();
+                }
+                s
+            }
+            VarValue::Map(m) => {
+                let mut s = String::new();
+                for (k, v) in m.iter() {
+                    if !s.is_empty() {
+                        s += " ";
+                    }
+                    s += &format!("{}={}", k, v.to_flat_string());
                 }
                 s
             }
@@ -143,16 +174,20 @@ impl VarValue {
     /// Return `true` if a variable is truthy:
     ///
     /// * non-zero integer value
+    /// * `true`
     /// * non-empty string
     /// * non-empty list (it must either have more than one item or the first item must be
-    /// non-empty string
+    ///   non-empty string
+    /// * non-empty map
     /// * result of shell execution with 0 exit code
     pub(crate) fn is_true(&self) -> bool {
         match self {
             VarValue::Undefined => false,
             VarValue::Int(i) => *i != 0,
+            VarValue::Bool(b) => *b,
             VarValue::Str(s) => !s.is_empty(),
-            VarValue::List(v) => !v.is_empty() && !v[0].is_empty(),
+            VarValue::List(v) => v.len() > 1 || (v.len() == 1 && !v[0].to_flat_string().is_empty()),
+            VarValue::Map(m) => !m.is_empty(),
             VarValue::Exec(er) => er.code == 0,
         }
     }
@@ -161,11 +196,14 @@ impl VarValue {
     ///
     /// * string is parsed as i64
     /// * shell execution is process exit code
-    /// * list - the first list item is parsed as i64
+    /// * boolean is 1 for `true` and 0 for `false`
+    /// * list - the first list item converted to integer
+    /// * map is always 0
     pub(crate) fn to_int(&self) -> i64 {
         match self {
-            VarValue::Undefined => 0,
+            VarValue::Undefined | VarValue::Map(_) => 0,
             VarValue::Int(i) => *i,
+            VarValue::Bool(b) => i64::from(*b),
             VarValue::Str(s) => {
                 if s.is_empty() {
                     0
@@ -173,13 +211,10 @@ impl VarValue {
                     s.parse::<i64>().unwrap_or(0)
                 }
             }
-            VarValue::List(v) => {
-                if v.is_empty() || v[0].is_empty() {
-                    0
-                } else {
-                    v[0].parse::<i64>().unwrap_or(0)
-                }
-            }
+            VarValue::List(v) => match v.first() {
+                None => 0,
+                Some(it) => it.to_int(),
+            },
             VarValue::Exec(ex) => {
                 if ex.stdout.is_empty() {
                     0
@@ -193,11 +228,89 @@ impl VarValue {
         }
     }
 
+    /// If any of values is a boolean one, converts it to the type of the other value: to a
+    /// string `true`/`false` if the other value is a string, and to an integer 1/0 otherwise.
+    /// Returns `None` if there is no boolean value
+    fn without_bool(&self, val: &VarValue) -> Option<(VarValue, VarValue)> {
+        let conv = |b: bool, other: &VarValue| match other {
+            VarValue::Str(_) => VarValue::Str(b.to_string()),
+            _ => VarValue::Int(i64::from(b)),
+        };
+        match (self, val) {
+            (VarValue::Bool(b), v) => {
+                let b = conv(*b, v);
+                let v = if let VarValue::Bool(b2) = v { VarValue::Int(i64::from(*b2)) } else { v.clone() };
+                Some((b, v))
+            }
+            (v, VarValue::Bool(b)) => Some((v.clone(), conv(*b, v))),
+            _ => None,
+        }
+    }
+
+    /// Converts a value to a list of values:
+    ///
+    /// * list - its items
+    /// * map - its keys
+    /// * result of shell execution - lines of its output, or empty list if the execution failed
+    /// * string - its lines, or its words if the string is a single line
+    /// * undefined - empty list
+    /// * other values - a list with one item
+    pub(crate) fn to_list(&self) -> Vec<VarValue> {
+        match self {
+            VarValue::Undefined => Vec::new(),
+            VarValue::Int(_) | VarValue::Bool(_) => vec![self.clone()],
+            VarValue::List(v) => v.clone(),
+            VarValue::Map(m) => m.keys().map(|k| VarValue::Str(k.clone())).collect(),
+            VarValue::Exec(ex) => {
+                if ex.code != 0 || ex.stdout.is_empty() {
+                    return Vec::new();
+                }
+                ex.stdout.trim_end().split('\n').map(|s| VarValue::Str(s.trim_end().to_string())).collect()
+            }
+            VarValue::Str(st) => {
+                if st.find('\n').is_some() {
+                    st.trim_end().split('\n').map(|s| VarValue::Str(s.trim_end().to_string())).collect()
+                } else {
+                    st.split_whitespace().map(|s| VarValue::Str(s.to_string())).collect()
+                }
+            }
+        }
+    }
+
+    /// Returns an item of a list or a map:
+    ///
+    /// * list - an item by its index converted to integer. Negative index counts from the
+    ///   end of the list: `-1` is the last item
+    /// * map - a value by its key converted to string
+    ///
+    /// Returns `Undefined` if the item does not exist or the value is neither a list nor a map
+    pub(crate) fn item(&self, idx: &VarValue) -> VarValue {
+        match self {
+            VarValue::List(v) => {
+                let i = idx.to_int();
+                let i = if i < 0 { i + v.len() as i64 } else { i };
+                if i < 0 {
+                    return VarValue::Undefined;
+                }
+                v.get(i as usize).cloned().unwrap_or(VarValue::Undefined)
+            }
+            VarValue::Map(m) => m.get(&idx.to_flat_string()).cloned().unwrap_or(VarValue::Undefined),
+            _ => VarValue::Undefined,
+        }
+    }
+
     /// Returns `true` if both values are equivalent
     fn cmp_eq(&self, val: &VarValue) -> bool {
+        if let Some((v1, v2)) = self.without_bool(val) {
+            return v1.cmp_eq(&v2);
+        }
         match self {
-            VarValue::Undefined => match val {
-                VarValue::Undefined => true,
+            VarValue::Undefined => matches!(val, VarValue::Undefined),
+            VarValue::Bool(_) => unreachable!(),
+            VarValue::Map(m1) => match val {
+                VarValue::Map(m2) => {
+                    m1.len() == m2.len() && m1.iter().all(|(k, v)| m2.get(k).is_some_and(|v2| v.cmp_eq(v2)))
+                }
                 _ => false,
             },
             VarValue::List(lst1) => match val {
@@ -206,7 +319,7 @@ impl VarValue {
                         false
                     } else {
                         for (idx, val) in lst1.iter().enumerate() {
-                            if val != &lst2[idx] {
+                            if !val.cmp_eq(&lst2[idx]) {
                                 return false;
                             }
                         }
@@ -215,11 +328,11 @@ impl VarValue {
                 }
                 VarValue::Exec(ex_val) => ex_val.code == 0 && ex_val.stdout.trim() == self.to_string(),
                 VarValue::Str(s) => &self.to_flat_string() == s,
-                VarValue::Int(i) => {
+                VarValue::Int(_) => {
                     if lst1.len() != 1 {
                         false
                     } else {
-                        lst1[0] == format!("{}", *i)
+                        lst1[0].cmp_eq(val)
                     }
                 }
                 _ => false,
@@ -246,7 +359,7 @@ impl VarValue {
                     if lst.len() != 1 {
                         false
                     } else {
-                        lst[0] == format!("{}", *i)
+                        lst[0].cmp_eq(self)
                     }
                 }
                 _ => false,
@@ -258,8 +371,13 @@ impl VarValue {
     /// NOTE: for execution result the successful execution (exit code 0) is always greater
     /// than failed one (exit code is not 0)
     fn cmp_greater(&self, val: &VarValue) -> bool {
+        if let Some((v1, v2)) = self.without_bool(val) {
+            return v1.cmp_greater(&v2);
+        }
         match self {
             VarValue::Undefined => false,
+            VarValue::Bool(_) => unreachable!(),
+            VarValue::Map(_) => matches!(val, VarValue::Undefined),
             VarValue::Exec(ex) => match val {
                 VarValue::Exec(ex_val) => {
                     if ex.code == 0 && ex_val.code != 0 {
@@ -290,7 +408,7 @@ impl VarValue {
                     if lst.is_empty() {
                         true
                     } else {
-                        let vv = lst[0].parse::<i64>().unwrap_or(0i64);
+                        let vv = lst[0].to_int();
                         *i > vv
                     }
                 }
@@ -303,7 +421,7 @@ impl VarValue {
                     if lst.is_empty() {
                         false
                     } else {
-                        let vv = lst[0].parse::<i64>().unwrap_or(0i64);
+                        let vv = lst[0].to_int();
                         vv > *i
                     }
                 }
@@ -312,7 +430,7 @@ impl VarValue {
                         true
                     } else {
                         for (idx, v) in lst.iter().enumerate() {
-                            if *v <= lst2[idx] {
+                            if !v.cmp_greater(&lst2[idx]) {
                                 return false;
                             }
                         }
@@ -328,11 +446,13 @@ impl VarValue {
     /// NOTE: for execution result the failed execution (exit code is not 0) is always less
     /// than the successful one (exit code is 0)
     fn cmp_less(&self, val: &VarValue) -> bool {
+        if let Some((v1, v2)) = self.without_bool(val) {
+            return v1.cmp_less(&v2);
+        }
         match self {
-            VarValue::Undefined => match val {
-                VarValue::Undefined => false,
-                _ => true,
-            },
+            VarValue::Undefined => !matches!(val, VarValue::Undefined),
+            VarValue::Bool(_) => unreachable!(),
+            VarValue::Map(_) => false,
             VarValue::Exec(ex) => match val {
                 VarValue::Exec(ex_val) => {
                     if ex.code == 0 && ex_val.code != 0 {
@@ -363,7 +483,7 @@ impl VarValue {
                     if lst.is_empty() {
                         false
                     } else {
-                        let vv = lst[0].parse::<i64>().unwrap_or(0i64);
+                        let vv = lst[0].to_int();
                         *i < vv
                     }
                 }
@@ -376,7 +496,7 @@ impl VarValue {
                     if lst.is_empty() {
                         false
                     } else {
-                        let vv = lst[0].parse::<i64>().unwrap_or(0i64);
+                        let vv = lst[0].to_int();
                         vv < *i
                     }
                 }
@@ -385,7 +505,7 @@ impl VarValue {
                         false
                     } else {
                         for (idx, v) in lst.iter().enumerate() {
-                            if *v >= lst2[idx] {
+                            if !v.cmp_less(&lst2[idx]) {
                                 return false;
                             }
                         }
@@ -415,7 +535,9 @@ impl VarValue {
     fn cmp_eq_or_less(&self, val: &VarValue) -> bool {
         !self.cmp_greater(val)
     }
-    /// Generic comparison function that calls the correct method depending on comparison sign
+    /// Generic comparison function that calls the correct method depending on comparison sign.
+    ///
+    /// NOTE: maps do not have order, so they can be only checked for equality
     pub(crate) fn cmp(&self, val: &VarValue, cmp_op: &str) -> bool {
         match cmp_op {
             "==" => self.cmp_eq(val),
@@ -430,6 +552,7 @@ impl VarValue {
 }
 
 /// Script variable
+#[derive(Clone)]
 pub struct Var {
     /// variable's name
     name: String,
@@ -443,6 +566,7 @@ impl Default for Var {
 }
 
 /// Variable manager: adds/removes variables, interpolates strings by substituting variable values
+#[derive(Clone)]
 pub(crate) struct VarMgr {
     /// values from CLI - user defined ones. Used to initialize recipe local variables
     pub(crate) free: Vec<String>,
@@ -450,6 +574,8 @@ pub(crate) struct VarMgr {
     pub(crate) recipe_vars: Vec<Var>,
     /// list of script global variables
     vars: Vec<Var>,
+    /// local variables of running user-defined functions: a frame per function call
+    frames: Vec<Vec<Var>>,
     /// verbosity level when displaying info for a user to standard output
     verbosity: usize,
     /// list of environment variables defined by the running script
@@ -458,7 +584,35 @@ pub(crate) struct VarMgr {
 
 impl VarMgr {
     pub(crate) fn new(verbosity: usize) -> Self {
-        VarMgr { recipe_vars: Vec::new(), vars: Vec::new(), free: Vec::new(), verbosity, env: HashMap::new() }
+        VarMgr {
+            recipe_vars: Vec::new(),
+            vars: Vec::new(),
+            frames: Vec::new(),
+            free: Vec::new(),
+            verbosity,
+            env: HashMap::new(),
+        }
+    }
+
+    /// Starts a user-defined function call: creates a new scope with function arguments
+    pub(crate) fn push_frame(&mut self, args: Vec<(String, VarValue)>) {
+        output!(self.verbosity, 2, "New function scope: {:?}", args);
+        self.frames.push(args.into_iter().map(|(name, value)| Var { name, value }).collect());
+    }
+
+    /// Finishes a user-defined function call: removes all its local variables
+    pub(crate) fn pop_frame(&mut self) {
+        self.frames.pop();
+    }
+
+    /// Returns the number of user-defined functions that are running now
+    pub(crate) fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns names and values of all script global variables in order of their creation
+    pub(crate) fn globals(&self) -> Vec<(String, VarValue)> {
+        self.vars.iter().map(|v| (v.name.clone(), v.value.clone())).collect()
     }
 
     /// Change or creates a recipe local variable.
@@ -477,8 +631,22 @@ impl VarMgr {
 
     /// First, it looks for recipe local variable. If it exists, its values changes. Otherwise,
     /// it modifies or create a global variable.
+    ///
+    /// Inside a user-defined function all assignments change or create function local variables.
     pub(crate) fn set_var(&mut self, name: &str, val: VarValue) {
         output!(self.verbosity, 2, "Setting a var {}", name);
+        if let Some(frame) = self.frames.last_mut() {
+            for v in frame.iter_mut() {
+                if v.name == name {
+                    output!(self.verbosity, 2, "Changing function local {} to {:?}", name, val);
+                    v.value = val;
+                    return;
+                }
+            }
+            output!(self.verbosity, 2, "New function local {}: {:?}", name, val);
+            frame.push(Var { name: name.to_string(), value: val });
+            return;
+        }
         for v in self.recipe_vars.iter_mut() {
             if v.name == name {
                 output!(self.verbosity, 2, "Changing recipe {} to {:?}", name, val);
@@ -500,11 +668,23 @@ impl VarMgr {
     /// Returns a value of a variable. First it looks for a recipe local. If it does not exist,
     /// looks for a global variable. The last check is to look for the environment variable.
     /// Returns `Undefined` if no variable exists.
+    ///
+    /// Inside a user-defined function, it looks for a function local variable first, and
+    /// recipe local variables are not visible.
     pub(crate) fn var(&self, name: &str) -> VarValue {
-        for v in self.recipe_vars.iter() {
-            if v.name == name {
-                output!(self.verbosity, 2, "Local recipe var {} found", name);
-                return v.value.clone();
+        if let Some(frame) = self.frames.last() {
+            for v in frame.iter() {
+                if v.name == name {
+                    output!(self.verbosity, 2, "Function local var {} found", name);
+                    return v.value.clone();
+                }
+            }
+        } else {
+            for v in self.recipe_vars.iter() {
+                if v.name == name {
+                    output!(self.verbosity, 2, "Local recipe var {} found", name);
+                    return v.value.clone();
+                }
             }
         }
         for v in self.vars.iter() {
@@ -651,6 +831,49 @@ mod var_test {
         assert_eq!(v1, VarValue::Int(123));
     }
 
+    #[test]
+    fn var_frames() {
+        let mut v = VarMgr::new(0);
+        v.set_var("abc", VarValue::Int(1));
+        v.set_recipe_var("rec", VarValue::Int(2));
+        v.push_frame(vec![("arg".to_string(), VarValue::Int(3))]);
+        assert_eq!(v.call_depth(), 1);
+        assert_eq!(v.var("abc"), VarValue::Int(1));
+        assert_eq!(v.var("rec"), VarValue::Undefined);
+        assert_eq!(v.var("arg"), VarValue::Int(3));
+        v.set_var("abc", VarValue::Int(10));
+        assert_eq!(v.var("abc"), VarValue::Int(10));
+        v.pop_frame();
+        assert_eq!(v.call_depth(), 0);
+        assert_eq!(v.var("abc"), VarValue::Int(1));
+        assert_eq!(v.var("rec"), VarValue::Int(2));
+        assert_eq!(v.var("arg"), VarValue::Undefined);
+    }
+
+    #[test]
+    fn typed_values() {
+        let mut m = BTreeMap::new();
+        m.insert("k".to_string(), VarValue::List(vec![VarValue::Int(1), VarValue::Bool(false)]));
+        let map = VarValue::Map(m);
+        let lst = map.item(&VarValue::from("k"));
+        assert_eq!(lst.item(&VarValue::Int(0)), VarValue::Int(1));
+        assert_eq!(lst.item(&VarValue::Int(-1)), VarValue::Bool(false));
+        assert_eq!(lst.item(&VarValue::Int(2)), VarValue::Undefined);
+        assert_eq!(map.item(&VarValue::from("none")), VarValue::Undefined);
+        assert_eq!(map.to_flat_string(), "k=1 false");
+        assert_eq!(lst.to_string(), "1\nfalse");
+        assert!(map.is_true());
+        assert!(!VarValue::Map(BTreeMap::new()).is_true());
+        assert!(!VarValue::Bool(false).is_true());
+
+        assert!(VarValue::Bool(true).cmp(&VarValue::Int(1), "=="));
+        assert!(VarValue::Bool(true).cmp(&VarValue::from("true"), "=="));
+        assert!(VarValue::Bool(true).cmp(&VarValue::Bool(false), ">"));
+        assert!(lst.cmp(&VarValue::List(vec![VarValue::from("1"), VarValue::Int(0)]), "=="));
+        assert!(map.cmp(&map.clone(), "=="));
+        assert!(map.cmp(&VarValue::from("k"), "!="));
+    }
+
     #[test]
     fn interpolate_no_matches() {
         let mut v = VarMgr::new(0);
@@ -1,22 +1,38 @@
+use std::collections::BTreeMap;
 use std::convert::From;
 use std::env;
 use std::fmt;
-use std::io::{self, Write};
+use std::fs;
+use std::io::{self, Read, Write};
 use std::iter::FromIterator;
 use std::mem;
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+use std::thread;
 use std::time::{Duration, Instant};
-use std::usize;
 
+use crate::cache::{self, CacheKey, CACHE_DIR};
+use crate::dotenv;
 use crate::errors::HakuError;
 use crate::func::{run_func, FuncResult};
-use crate::ops::{is_flag_on, Op, Seq, FLAG_PASS, FLAG_QUIET};
-use crate::parse::{DisabledRecipe, HakuFile};
+use crate::ops::{is_flag_on, Op, Seq};
+pub use crate::parse::DisabledRecipe;
+use crate::parse::HakuFile;
+use crate::uptodate::{expand_paths, is_up_to_date};
+
+pub use crate::ops::{RecipeArg, RecipeDep, FLAG_PASS, FLAG_QUIET};
+use crate::signal;
+pub use crate::stream::OutputMode;
+use crate::stream::{line_prefix, run_streamed};
+use crate::timeout::{new_group, parse_duration, wait_child};
 use crate::var::{ExecResult, VarMgr, VarValue};
 
 /// Name of a recipe that is executed if no recipe is set by a caller
 const DEFAULT_RECIPE: &str = "_default";
+/// Maximum number of nested user-defined function calls. It is kept low so that deep
+/// recursion stays within the default thread stack
+const MAX_CALL_DEPTH: usize = 64;
 
 #[macro_export]
 macro_rules! output {
@@ -37,11 +53,45 @@ macro_rules! output {
     };
 }
 
+/// Runs a command and collects its output like `Command::output` does, but kills the
+/// command if it does not finish before `deadline`. Returns `None` in this case
+fn output_with_deadline(cmd: &mut Command, deadline: Option<Instant>) -> io::Result<Option<Output>> {
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    let readers: Vec<thread::JoinHandle<Vec<u8>>> = vec![
+        child.stdout.take().map(|s| Box::new(s) as Box<dyn Read + Send>),
+        child.stderr.take().map(|s| Box::new(s) as Box<dyn Read + Send>),
+    ]
+    .into_iter()
+    .map(|src| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut s) = src {
+                let _ = s.read_to_end(&mut buf);
+            }
+            buf
+        })
+    })
+    .collect();
+    // the reader threads finish even after a timeout: all processes that keep the output
+    // open are killed together with the command
+    let status = wait_child(&mut child, deadline)?;
+    let mut outs = readers.into_iter().map(|th| th.join().unwrap_or_default());
+    let status = match status {
+        Some(st) => st,
+        None => return Ok(None),
+    };
+    let stdout = outs.next().unwrap_or_default();
+    let stderr = outs.next().unwrap_or_default();
+    Ok(Some(Output { status, stdout, stderr }))
+}
+
 /// Human-readable formatting of a time duration:
-///   == 0    => 0ms
-///    > 60s  => 10m30s
-///    > 0s   => 30s234ms
-///    < 0s   => 234ms
+///
+/// * `== 0`   => 0ms
+/// * `>= 60s` => 10m30s
+/// * `> 0s`   => 30s234ms
+/// * `< 1s`   => 234ms
 fn human_duration(dur: Duration) -> String {
     let sec = dur.as_secs();
     if sec >= 60 {
@@ -70,11 +120,34 @@ pub struct RunOpts {
     dry_run: bool,
     /// `true` - show time taken by a recipe
     show_time: bool,
+    /// maximum number of recipes that can be executed at the same time
+    jobs: usize,
+    /// `false` - ignore `cache` attribute: always run recipes and do not save their hashes
+    use_cache: bool,
+    /// how the output of shell commands is displayed (a recipe attribute can override it)
+    output: OutputMode,
+    /// dotenv files to load into the script environment before running a recipe
+    env_files: Vec<String>,
+    /// script global variables set by a caller before the script starts
+    vars: Vec<(String, String)>,
+    /// `true` - SIGINT, SIGTERM, and SIGHUP stop the script instead of killing the process
+    signals: bool,
 }
 
 impl Default for RunOpts {
     fn default() -> Self {
-        RunOpts { dry_run: false, feats: Vec::new(), verbosity: 0, show_time: false }
+        RunOpts {
+            dry_run: false,
+            feats: Vec::new(),
+            verbosity: 0,
+            show_time: false,
+            jobs: 1,
+            use_cache: true,
+            output: OutputMode::Plain,
+            env_files: Vec::new(),
+            vars: Vec::new(),
+            signals: false,
+        }
     }
 }
 
@@ -102,6 +175,146 @@ impl RunOpts {
         self.show_time = show;
         self
     }
+
+    /// Enables or disables skipping recipes which inputs have not changed since their last
+    /// successful run
+    pub fn with_cache(mut self, use_cache: bool) -> Self {
+        self.use_cache = use_cache;
+        self
+    }
+
+    /// Sets how the output of shell commands is displayed: as is, or every line prefixed
+    /// with the recipe name and, optionally, the time elapsed since the start
+    pub fn with_output(mut self, output: OutputMode) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Sets dotenv files which variables are added to the script environment before the
+    /// script starts
+    pub fn with_env_files(mut self, files: Vec<String>) -> Self {
+        self.env_files = files;
+        self
+    }
+
+    /// Sets script global variables before the script starts, so a script can override
+    /// them only with plain assignment, and `?=` assignments keep their values
+    pub fn with_vars(mut self, vars: Vec<(String, String)>) -> Self {
+        self.vars = vars;
+        self
+    }
+
+    /// Enables handling SIGINT, SIGTERM, and SIGHUP while a script runs (Unix only): a signal
+    /// is forwarded to running external commands, and the script stops with an error after
+    /// running its cleanup code. The handlers are installed on the first run and are never
+    /// removed, but outside a run and while `pause` waits for input a signal terminates the
+    /// process as usual. Disabled by default, so an application that embeds the engine keeps
+    /// its own signal handling
+    pub fn with_signals(mut self, enable: bool) -> Self {
+        self.signals = enable;
+        self
+    }
+
+    /// Sets the maximum number of recipes that run concurrently. `1` executes all recipes
+    /// one by one, `0` uses the number of available CPUs
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = if jobs == 0 { thread::available_parallelism().map(|n| n.get()).unwrap_or(1) } else { jobs };
+        self
+    }
+}
+
+/// Recipe attributes declared with `#[...]` right before the recipe
+#[derive(Clone, Debug, Default)]
+pub struct RecipeAttrs {
+    /// files produced by the recipe (`#[outputs(...)]`)
+    pub outputs: Vec<String>,
+    /// files the recipe outputs are built from (`#[sources(...)]`)
+    pub sources: Vec<String>,
+    /// skip the recipe if its inputs have not changed since the last successful run (`#[cache]`)
+    pub cache: bool,
+    /// names of environment variables that the cached recipe depends on (`#[cache(VAR1, VAR2)]`)
+    pub cache_env: Vec<String>,
+    /// how the recipe displays output of its shell commands (`#[output(prefix)]`)
+    pub output: Option<OutputMode>,
+    /// the maximum time the recipe can run (`#[timeout(30s)]`)
+    pub timeout: Option<Duration>,
+    /// restart failed shell commands of the recipe (`#[retry(3, backoff=2s)]`)
+    pub retry: Option<Retry>,
+    /// recipes to run after the recipe body, even if it fails (`#[finally(teardown)]`)
+    pub finally: Vec<String>,
+    /// recipes to run if the recipe body fails (`#[on_failure(notify)]`)
+    pub on_failure: Vec<String>,
+}
+
+impl RecipeAttrs {
+    /// Adds a value of a single attribute
+    fn apply(&mut self, name: &str, vals: &[String]) {
+        match name {
+            "outputs" => self.outputs.extend(vals.iter().cloned()),
+            "sources" => self.sources.extend(vals.iter().cloned()),
+            "cache" => {
+                self.cache = true;
+                self.cache_env.extend(vals.iter().cloned());
+            }
+            "output" => self.output = vals.first().and_then(|v| OutputMode::from_name(v)),
+            "timeout" => self.timeout = vals.first().and_then(|v| parse_duration(v)),
+            "retry" => self.retry = Retry::from_attr(vals),
+            "finally" => self.finally.extend(vals.iter().cloned()),
+            "on_failure" | "on-failure" => self.on_failure.extend(vals.iter().cloned()),
+            _ => {}
+        }
+    }
+}
+
+/// How to restart a failed shell command: `#[retry(3, backoff=2s)]`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retry {
+    /// the maximum number of restarts after the first failure
+    pub count: u32,
+    /// the delay before every restart
+    pub backoff: Duration,
+}
+
+impl Retry {
+    /// Parses attribute values: the number of restarts and optional `backoff=DURATION`.
+    /// Returns `None` if any value is invalid or the number of restarts is zero
+    pub(crate) fn from_attr(vals: &[String]) -> Option<Retry> {
+        let mut retry = Retry { count: 0, backoff: Duration::from_secs(0) };
+        for (idx, val) in vals.iter().enumerate() {
+            if let Some(d) = val.strip_prefix("backoff=").or_else(|| val.strip_prefix("delay=")) {
+                retry.backoff = parse_duration(d)?;
+            } else if idx == 0 {
+                retry.count = val.parse::<u32>().ok()?;
+            } else {
+                return None;
+            }
+        }
+        if retry.count == 0 {
+            return None;
+        }
+        Some(retry)
+    }
+}
+
+/// Attributes of a single script line, e.g. `#[timeout(5s)]` or `#[retry(3)]` right
+/// before a command
+#[derive(Clone, Debug, Default)]
+struct LineAttrs {
+    /// the time limit for commands of the line
+    timeout: Option<Duration>,
+    /// restart the line command if it fails
+    retry: Option<Retry>,
+}
+
+impl LineAttrs {
+    /// Adds a value of a single attribute
+    fn apply(&mut self, name: &str, vals: &[String]) {
+        match name {
+            "timeout" => self.timeout = vals.first().and_then(|v| parse_duration(v)),
+            "retry" => self.retry = Retry::from_attr(vals),
+            _ => {}
+        }
+    }
 }
 
 /// Recipe detailed information
@@ -111,8 +324,10 @@ pub struct RecipeDesc {
     pub name: String,
     /// recipe's description from its doc comments
     pub desc: String,
+    /// list of features when the recipe is enabled, empty if the recipe is always enabled
+    pub feat: String,
     /// a list of recipes this one depends on
-    pub depends: Vec<String>,
+    pub depends: Vec<RecipeDep>,
     /// is it a system recipe? (system recipes are not show by default)
     pub system: bool,
     /// the recipe location (file and line)
@@ -120,7 +335,9 @@ pub struct RecipeDesc {
     /// recipe-wide flags (i.e., echo off, skip errors)
     pub flags: u32,
     /// recipe local variables (they override any global variables with the same names)
-    pub vars: Vec<String>,
+    pub vars: Vec<RecipeArg>,
+    /// recipe attributes
+    pub attrs: RecipeAttrs,
 }
 
 impl fmt::Display for RecipeDesc {
@@ -164,12 +381,41 @@ enum Condition {
     /// * the final value - for stops when the current value reaches or exceeds the final one
     /// * step - every cycle the current value is changes by the step
     ForInt(String, i64, i64, i64),
-    /// the engine is in a for loop that runs through a list of strings (i.e., result of
-    /// executing an external command or list of values separated with whitespace):
+    /// the engine is in a for loop that runs through a list of values (i.e., result of
+    /// executing an external command, list of values separated with whitespace, items of
+    /// a list variable, or keys of a map variable):
     ///
     /// * loop variable name (its value changed every cycle)
     /// * list of values (changed every cycle - the used value is removed from the list)
-    ForList(String, Vec<String>),
+    ForList(String, Vec<VarValue>),
+    /// the engine is in a try block:
+    ///
+    /// * the part of the block that is executed
+    /// * what to do after `finally` part is done
+    Try(TryStage, TryExit),
+}
+
+/// The part of try block the engine executes
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TryStage {
+    Body,
+    Catch,
+    Finally,
+}
+
+/// What the engine does when `finally` part of a try block is done
+#[derive(Clone, Debug)]
+enum TryExit {
+    /// continue from the line after the block
+    Next,
+    /// return the error that has not been caught by the block
+    Error(HakuError),
+    /// `break` has left the block: break the loop that contains the block
+    Break,
+    /// `continue` has left the block: start the next cycle of the loop that contains the block
+    Continue,
+    /// `return` has left the block: leave the function or the recipe
+    Return,
 }
 
 /// Describes a condition(loop) the engine is in
@@ -209,6 +455,47 @@ pub struct Engine {
     pub(crate) cwd: PathBuf,
     /// directory change stack (for "cd -" command)
     pub(crate) cwd_history: Vec<PathBuf>,
+    /// directory to keep hashes of recipes marked with `cache` attribute
+    cache_dir: PathBuf,
+    /// recipe calls that have been executed since the engine started
+    finished: Vec<JobId>,
+    /// The list of user-defined functions
+    funcs: Vec<UserFunc>,
+    /// the value of the last executed `return` inside a user-defined function
+    ret_val: Option<VarValue>,
+    /// the time the engine was created (used to show elapsed time in output prefixes)
+    started: Instant,
+    /// the name of the running recipe, empty while executing script headers
+    cur_recipe: String,
+    /// flags of the running recipe (`@` and `-`), functions it calls inherit them
+    recipe_flags: u32,
+    /// how the running recipe displays output of shell commands
+    out_mode: OutputMode,
+    /// the time the running recipe must finish by
+    recipe_deadline: Option<Instant>,
+    /// restart settings for shell commands of the running recipe
+    recipe_retry: Option<Retry>,
+    /// attributes that have been read but not applied to a line yet
+    pending_attrs: LineAttrs,
+    /// attributes of the line being executed
+    line_attrs: LineAttrs,
+    /// names of `finally` and `on_failure` recipes being executed (to avoid endless loops)
+    hooks_running: Vec<String>,
+    /// the number of signals this engine has already reported (see `check_interrupt`)
+    signals_seen: usize,
+}
+
+/// User-defined function: `fn name(arg1, arg2)`
+#[derive(Debug, Clone)]
+struct UserFunc {
+    /// function's name
+    name: String,
+    /// names of function arguments
+    params: Vec<String>,
+    /// the number of file (in `engine.files` list)
+    file: usize,
+    /// the line number of the function declaration in operation list
+    line: usize,
 }
 
 /// Describes a recipe location
@@ -223,16 +510,59 @@ pub struct RecipeLoc {
 }
 
 /// Describes a recipe
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct RecipeItem {
     /// recipe's name
     name: String,
     /// recipe's location
     loc: RecipeLoc,
     /// recipe's local variables (overrides existing global variables with the same names)
-    vars: Vec<String>,
+    vars: Vec<RecipeArg>,
+    /// arguments passed by a recipe that depends on this one. `None` - the recipe uses free
+    /// arguments of the current recipe call
+    args: Option<Vec<String>>,
     /// global recipe flags (i.e., echo off)
     flags: u32,
+    /// indices of the recipes (in the same list) that must finish before this one starts
+    deps: Vec<usize>,
+    /// recipe attributes
+    attrs: RecipeAttrs,
+}
+
+/// Identifies a recipe call: recipe name and its arguments
+type JobId = (String, Option<Vec<String>>);
+
+impl RecipeItem {
+    /// Returns the name of a recipe call to display: the recipe name with its arguments
+    fn job_name(&self) -> String {
+        match self.args {
+            None => self.name.clone(),
+            Some(ref args) => format!("{}({})", self.name, args.join(", ")),
+        }
+    }
+
+    /// Returns the identity of a recipe call: the same recipe called with different
+    /// arguments is executed once for every set of arguments
+    fn job_id(&self) -> JobId {
+        (self.name.clone(), self.args.clone())
+    }
+}
+
+/// State of a recipe while the engine executes recipes in parallel
+#[derive(Clone, Copy, PartialEq)]
+enum JobState {
+    Waiting,
+    Running,
+    Done,
+}
+
+/// A recipe to run with its arguments passed from a caller
+#[derive(Clone, Debug, Default)]
+pub struct RecipeCall {
+    /// recipe's name
+    pub name: String,
+    /// values to initialize recipe variables
+    pub args: Vec<String>,
 }
 
 /// Recipe content
@@ -245,6 +575,32 @@ pub struct RecipeContent {
     pub enabled: bool,
 }
 
+/// What a node of a recipe dependency tree is
+#[derive(Clone, Debug, PartialEq)]
+pub enum DepKind {
+    /// an active recipe, its dependencies are listed in the node
+    Recipe,
+    /// an active recipe which dependencies are already listed earlier in the tree
+    Seen,
+    /// the recipe is one of its own (direct or indirect) dependencies
+    Cycle,
+    /// a disabled recipe and its feature condition, e.g. `#[os(linux)]`
+    Disabled(String),
+    /// there is no recipe with this name
+    Missing,
+}
+
+/// A node of a recipe dependency tree built by `Engine::dep_tree`
+#[derive(Clone, Debug)]
+pub struct DepTree {
+    /// recipe's name
+    pub name: String,
+    /// the script the recipe is declared in, empty for missing recipes
+    pub file: String,
+    pub kind: DepKind,
+    pub depends: Vec<DepTree>,
+}
+
 impl Engine {
     pub fn new(opts: RunOpts) -> Self {
         #[cfg(windows)]
@@ -259,6 +615,7 @@ impl Engine {
                 PathBuf::new()
             }
         };
+        let cache_dir = cwd.join(CACHE_DIR);
         Engine {
             files: Vec::new(),
             included: Vec::new(),
@@ -271,6 +628,20 @@ impl Engine {
             shell,
             cwd,
             cwd_history: Vec::new(),
+            cache_dir,
+            finished: Vec::new(),
+            funcs: Vec::new(),
+            ret_val: None,
+            started: Instant::now(),
+            cur_recipe: String::new(),
+            recipe_flags: 0,
+            out_mode: OutputMode::Plain,
+            recipe_deadline: None,
+            recipe_retry: None,
+            pending_attrs: LineAttrs::default(),
+            line_attrs: LineAttrs::default(),
+            hooks_running: Vec::new(),
+            signals_seen: 0,
         }
     }
 
@@ -306,6 +677,7 @@ impl Engine {
         self.included.push(filepath.to_string());
         self.run_header(self.files.len() - 1)?;
         self.detect_recipes();
+        self.detect_functions();
         Ok(())
     }
 
@@ -317,6 +689,7 @@ impl Engine {
         self.files.push(hk);
         self.run_header(self.files.len() - 1)?;
         self.detect_recipes();
+        self.detect_functions();
         Ok(())
     }
 
@@ -334,7 +707,7 @@ impl Engine {
                 Op::Recipe(_, _, _, _) => break,
                 Op::Comment(_) | Op::DocComment(_) => { /* just continue */ }
                 Op::Include(flags, path) => {
-                    let inc_path = self.varmgr.interpolate(&path, true);
+                    let inc_path = self.varmgr.interpolate(path, true);
                     output!(self.opts.verbosity, 3, "        !!INCLUDE - {}", inc_path);
                     to_include.push(inc_path);
                     to_include_flags.push(*flags);
@@ -359,7 +732,7 @@ impl Engine {
 
     /// Returns `true` if the name of a recipe is a system one. System recipes should not
     /// be displayed by a caller
-    fn is_system_recipe(name: &str) -> bool {
+    pub fn is_system_recipe(name: &str) -> bool {
         name == "_default" || name == "_before" || name == "_after"
     }
 
@@ -372,32 +745,34 @@ impl Engine {
     fn detect_recipes(&mut self) {
         for (file_idx, hk) in self.files.iter().enumerate() {
             let mut desc = String::new();
+            let mut feat = String::new();
+            let mut attrs = RecipeAttrs::default();
             for (line_idx, op) in hk.ops.iter().enumerate() {
                 match op.op {
-                    Op::Feature(_, _) => {}
+                    Op::Feature(_, ref s) => feat = s.clone(),
                     Op::DocComment(ref s) => desc = self.varmgr.interpolate(s, true),
+                    Op::Attr(ref name, ref vals) => attrs.apply(name, vals),
                     Op::Recipe(ref nm, flags, ref vars, ref deps) => {
-                        let mut recipe = RecipeDesc {
+                        let recipe = RecipeDesc {
                             name: nm.clone(),
                             desc: desc.clone(),
+                            feat: mem::take(&mut feat),
                             loc: RecipeLoc { line: line_idx, file: file_idx, script_line: op.line },
-                            depends: Vec::new(),
-                            system: Engine::is_system_recipe(&nm),
+                            depends: deps.clone(),
+                            system: Engine::is_system_recipe(nm),
                             vars: vars.clone(),
                             flags,
+                            attrs: attrs.clone(),
                         };
-                        if !deps.is_empty() {
-                            for d in deps.iter() {
-                                recipe.depends.push(d.to_string());
-                            }
-                        }
-
                         self.recipes.push(recipe);
                         desc.clear();
+                        attrs = RecipeAttrs::default();
                     }
                     Op::Comment(_) => { /* do not change anything */ }
                     _ => {
                         desc.clear();
+                        feat.clear();
+                        attrs = RecipeAttrs::default();
                     }
                 }
             }
@@ -405,13 +780,35 @@ impl Engine {
         self.recipes.sort_by(|a, b| a.name.partial_cmp(&b.name).unwrap());
     }
 
+    /// Builds a list of user-defined functions. If a few functions have the same name,
+    /// the first loaded one is used
+    fn detect_functions(&mut self) {
+        self.funcs.clear();
+        for (file_idx, hk) in self.files.iter().enumerate() {
+            for (line_idx, op) in hk.ops.iter().enumerate() {
+                if let Op::Function(ref name, ref params) = op.op {
+                    if self.funcs.iter().any(|f| &f.name == name) {
+                        continue;
+                    }
+                    self.funcs.push(UserFunc {
+                        name: name.clone(),
+                        params: params.clone(),
+                        file: file_idx,
+                        line: line_idx,
+                    });
+                }
+            }
+        }
+    }
+
     /// Returns full path to a script by its number (the number must be less than
     /// `engine.files` length
     pub fn file_name(&self, file_idx: usize) -> Result<&str, HakuError> {
-        if file_idx >= self.files.len() {
-            return Err(HakuError::FileNotLoaded(file_idx));
+        // a script loaded from a string does not have a name
+        match self.included.get(file_idx) {
+            Some(name) if file_idx < self.files.len() => Ok(name),
+            _ => Err(HakuError::FileNotLoaded(file_idx)),
         }
-        Ok(&self.included[file_idx])
     }
 
     /// Returns info about all loaded available recipes
@@ -422,9 +819,9 @@ impl Engine {
     /// Returns info about all loaded disabled recipes
     pub fn disabled_recipes(&self) -> Vec<DisabledRecipe> {
         let mut v = Vec::new();
-        for file in self.files.iter() {
+        for (idx, file) in self.files.iter().enumerate() {
             for ds in file.disabled.iter() {
-                v.push(ds.clone());
+                v.push(DisabledRecipe { file: idx, ..ds.clone() });
             }
         }
         v
@@ -517,6 +914,74 @@ impl Engine {
         Err(HakuError::RecipeNotFoundError(name.to_string()))
     }
 
+    /// Builds dependency trees for recipes `names`. Dependencies are resolved the same way
+    /// as they are when a recipe runs. If `names` is empty, the trees are built for all
+    /// recipes that are not dependencies of other recipes, except system ones, and then for
+    /// recipes that are not in any tree yet (e.g., recipes that make a cycle). Every recipe
+    /// is expanded only once: next time the recipe is marked `DepKind::Seen`.
+    pub fn dep_tree(&self, names: &[String]) -> Vec<DepTree> {
+        let disabled = self.disabled_recipes();
+        let mut seen = Vec::new();
+        let mut path = Vec::new();
+        if !names.is_empty() {
+            return names.iter().map(|name| self.dep_node(name, &disabled, &mut seen, &mut path)).collect();
+        }
+
+        let mut trees = Vec::new();
+        for r in self.recipes.iter() {
+            let is_dep = self.recipes.iter().any(|o| o.depends.iter().any(|d| d.name == r.name));
+            if !r.system && !is_dep && !seen.contains(&r.name) {
+                trees.push(self.dep_node(&r.name, &disabled, &mut seen, &mut path));
+            }
+        }
+        for r in self.recipes.iter() {
+            if !r.system && !seen.contains(&r.name) {
+                trees.push(self.dep_node(&r.name, &disabled, &mut seen, &mut path));
+            }
+        }
+        trees
+    }
+
+    fn dep_node(
+        &self,
+        name: &str,
+        disabled: &[DisabledRecipe],
+        seen: &mut Vec<String>,
+        path: &mut Vec<String>,
+    ) -> DepTree {
+        let mut node =
+            DepTree { name: name.to_string(), file: String::new(), kind: DepKind::Recipe, depends: Vec::new() };
+        let desc = match self.find_recipe(name) {
+            Ok(desc) => desc,
+            Err(_) => {
+                match disabled.iter().find(|d| d.name == name) {
+                    Some(d) => {
+                        node.file = self.file_name(d.file).unwrap_or("").to_string();
+                        node.kind = DepKind::Disabled(d.feat.clone());
+                    }
+                    None => node.kind = DepKind::Missing,
+                }
+                return node;
+            }
+        };
+        node.file = self.file_name(desc.loc.file).unwrap_or("").to_string();
+        if path.iter().any(|p| p == name) {
+            node.kind = DepKind::Cycle;
+            return node;
+        }
+        if seen.iter().any(|p| p == name) {
+            node.kind = DepKind::Seen;
+            return node;
+        }
+        seen.push(name.to_string());
+        path.push(name.to_string());
+        for dep in desc.depends.iter() {
+            node.depends.push(self.dep_node(&dep.name, disabled, seen, path));
+        }
+        path.pop();
+        node
+    }
+
     /// Returns a list of unique user-defined features found in loaded scripts
     pub fn user_features(&self) -> Vec<String> {
         let mut v: Vec<String> = Vec::new();
@@ -560,16 +1025,12 @@ impl Engine {
     /// scripts.
     pub fn run_recipe(&mut self, name: &str) -> Result<(), HakuError> {
         output!(self.opts.verbosity, 1, "Running SECTION '{}'", name);
-        let sec_res = if name.is_empty() {
-            match self.find_recipe(DEFAULT_RECIPE) {
-                Ok(loc) => Some(loc),
-                _ => None,
-            }
-        } else {
-            Some(self.find_recipe(name)?)
-        };
+        let sec_res =
+            if name.is_empty() { self.find_recipe(DEFAULT_RECIPE).ok() } else { Some(self.find_recipe(name)?) };
 
-        self.exec_init()?;
+        self.finished.clear();
+        let _signals = self.catch_signals();
+        self.start_run()?;
         if let Some(sec) = sec_res {
             // default recipe can be missing
             return self.exec_recipe(sec.loc);
@@ -577,6 +1038,31 @@ impl Engine {
         Err(HakuError::DefaultRecipeError)
     }
 
+    /// Executes a few recipes one by one, every recipe gets its own list of free arguments.
+    /// All recipes must exist and be active, otherwise nothing is executed. Script headers
+    /// are executed only once before the first recipe. A dependency that has been already
+    /// executed for one of previous recipes is not executed again. If the list is empty,
+    /// DEFAULT_RECIPE is executed.
+    pub fn run_recipes(&mut self, calls: &[RecipeCall]) -> Result<(), HakuError> {
+        if calls.is_empty() {
+            return self.run_recipe("");
+        }
+        let mut locs = Vec::new();
+        for call in calls.iter() {
+            locs.push(self.find_recipe(&call.name)?.loc);
+        }
+
+        self.finished.clear();
+        let _signals = self.catch_signals();
+        self.start_run()?;
+        for (call, loc) in calls.iter().zip(locs) {
+            output!(self.opts.verbosity, 1, "Running SECTION '{}'", call.name);
+            self.set_free_args(&call.args);
+            self.exec_recipe(loc)?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn set_shell(&mut self, new_shell: Vec<String>) -> FuncResult {
         // TODO: some sanity checks?
         if new_shell.is_empty() {
@@ -611,21 +1097,103 @@ impl Engine {
         Ok(VarValue::from(1))
     }
 
+    /// Returns the path relative to the current working directory of the script: it differs
+    /// from the process working directory after `cd` command
+    pub(crate) fn full_path(&self, path: &str) -> PathBuf {
+        if self.cwd_history.is_empty() {
+            PathBuf::from(path)
+        } else {
+            self.cwd.join(path)
+        }
+    }
+
+    /// Loads variables from a dotenv file into the script environment: they are passed to
+    /// all external commands and can be read as usual variables. A relative path is
+    /// resolved from the current script directory (see `cd`). Returns the number of loaded
+    /// variables
+    pub(crate) fn load_env_file(&mut self, path: &str) -> Result<usize, HakuError> {
+        let full_path = self.full_path(path);
+        output!(self.opts.verbosity, 1, "Loading env file {:?}", full_path);
+        let content =
+            fs::read_to_string(&full_path).map_err(|e| HakuError::EnvFileError(path.to_string(), e.to_string()))?;
+        let env = &self.varmgr.env;
+        let vars = dotenv::parse(&content, |name| env.get(name).cloned().or_else(|| env::var(name).ok()))
+            .map_err(|e| HakuError::EnvFileError(path.to_string(), e))?;
+        let cnt = vars.len();
+        for (name, value) in vars.into_iter() {
+            output!(self.opts.verbosity, 2, "Change env var {} to '{}'", name, value);
+            self.varmgr.env.insert(name, value);
+        }
+        Ok(cnt)
+    }
+
+    /// Makes signals stop the script instead of killing the process until the returned
+    /// guard is dropped. Does nothing if a caller has not enabled it with `with_signals`
+    fn catch_signals(&self) -> Option<signal::RunGuard> {
+        if self.opts.signals {
+            Some(signal::catch())
+        } else {
+            None
+        }
+    }
+
+    /// Prepares the engine to run a script: forgets earlier signals, loads dotenv files
+    /// and variables passed by a caller, and executes script headers
+    fn start_run(&mut self) -> Result<(), HakuError> {
+        signal::reset();
+        self.signals_seen = 0;
+        for path in self.opts.env_files.clone().iter() {
+            self.load_env_file(path)?;
+        }
+        for (name, value) in self.opts.vars.clone().into_iter() {
+            output!(self.opts.verbosity, 1, "Setting var {} from command line to '{}'", name, value);
+            self.varmgr.set_var(&name, VarValue::Str(value));
+        }
+        self.exec_init()
+    }
+
+    /// Executes script headers (all lines before the first recipe in all loaded scripts)
+    /// and returns names and values of global variables in order of their creation
+    pub fn global_vars(&mut self) -> Result<Vec<(String, VarValue)>, HakuError> {
+        let _signals = self.catch_signals();
+        self.start_run()?;
+        Ok(self.varmgr.globals())
+    }
+
     /// Executes a script from the first line until the first recipe or end of the script.
     fn exec_file_init(&mut self, file: usize) -> Result<(), HakuError> {
-        let cnt = self.files[file].ops.len();
         let mut i = 0;
+        loop {
+            match self.exec_file_lines(file, i) {
+                Ok(()) => return Ok(()),
+                Err(e) => i = self.catch_error(file, e)?,
+            }
+        }
+    }
+
+    /// Executes script header lines starting from line `start`
+    fn exec_file_lines(&mut self, file: usize, start: usize) -> Result<(), HakuError> {
+        let cnt = self.files[file].ops.len();
+        let mut i = start;
         while i < cnt {
             let op = self.files[file].ops[i].clone();
             self.real_line = op.line;
             self.file_idx = file;
+            self.check_interrupt()?;
             match op.op {
-                Op::Recipe(_, _, _, _) | Op::Return => return Ok(()),
+                Op::Recipe(_, _, _, _) => return Ok(()),
+                Op::Return(_) => match self.exec_return(file)? {
+                    Some(next) => i = next,
+                    None => return Ok(()),
+                },
+                Op::Function(_, _) => {
+                    i = self.find_end(file, i + 1, "fn")?;
+                }
                 Op::Include(_, _) => {
                     i += 1;
                 }
                 Op::Error(msg) => return Err(HakuError::UserError(format!("{} at line {}", msg, op.line))),
-                Op::DocComment(_) | Op::Comment(_) => {
+                Op::DocComment(_) | Op::Comment(_) | Op::Attr(_, _) | Op::Feature(_, _) => {
                     i += 1;
                 }
                 Op::Shell(flags, cmd) => {
@@ -649,7 +1217,14 @@ impl Engine {
                     i += 1;
                 } // top level - func value is dropped
                 Op::StmtClose => {
-                    let next = self.exec_end()?;
+                    if self.return_pending() {
+                        match self.exec_return(file)? {
+                            Some(next) => i = next,
+                            None => return Ok(()),
+                        }
+                        continue;
+                    }
+                    let next = self.exec_end(file)?;
                     if next == 0 {
                         i += 1;
                     } else {
@@ -665,7 +1240,6 @@ impl Engine {
                     }
                 }
                 Op::While(ops) => {
-                    // must have exact 1 op
                     let ok = self.exec_while(&ops, i)?;
                     if ok {
                         i += 1;
@@ -687,7 +1261,17 @@ impl Engine {
                 }
                 Op::ElseIf(ops) => {
                     i = self.exec_elseif(&ops, file, i)?;
-                } // must have exact 1 op
+                }
+                Op::Try => {
+                    self.exec_try(i);
+                    i += 1;
+                }
+                Op::Catch(_) => {
+                    i = self.exec_catch(file, i)?;
+                }
+                Op::Finally => {
+                    i = self.exec_finally(i)?;
+                }
                 Op::Cd(flags, p) => {
                     self.exec_cd(flags, &p)?;
                     i += 1;
@@ -716,57 +1300,93 @@ impl Engine {
         Ok(())
     }
 
-    /// Adds a recipe to the list of recipes to execute before running a given one.
+    /// Adds a recipe and all its dependencies to the list of recipes to execute. Every recipe
+    /// is added only once for the same `args`, so a recipe shared by a few dependencies runs
+    /// only once. `path` is the chain of recipes that led to this one: if the recipe is
+    /// already in the chain, the dependency graph has a cycle. Returns the index of the
+    /// recipe in the list.
     /// Used only internally.
     fn push_recipe(
         &mut self,
         loc: RecipeLoc,
-        found: Option<&[RecipeItem]>,
-        parent: Option<&[String]>,
-    ) -> Result<Vec<RecipeItem>, HakuError> {
+        args: Option<Vec<String>>,
+        list: &mut Vec<RecipeItem>,
+        path: &mut Vec<String>,
+    ) -> Result<usize, HakuError> {
         let op = self.files[loc.file].ops[loc.line].clone();
-        let mut sec_item: RecipeItem = RecipeItem {
-            name: String::new(),
-            loc: RecipeLoc { file: 0, line: 0, script_line: 0 },
-            vars: Vec::new(),
-            flags: 0,
-        };
         output!(self.opts.verbosity, 2, "Checking recipe: {:?}", op);
-        let mut vc: Vec<RecipeItem> = Vec::new();
-        let mut parents: Vec<String> = match parent {
-            None => Vec::new(),
-            Some(p) => p.iter().map(|a| a.to_string()).collect(),
+        self.real_line = op.line;
+        self.file_idx = loc.file;
+        let attrs = self.recipe_attrs(&loc);
+        let (name, flags, vars, deps) = match op.op {
+            Op::Recipe(name, flags, vars, deps) => (name, flags, vars, deps),
+            _ => unreachable!(),
         };
-        match op.op {
-            Op::Recipe(name, flags, vars, deps) => {
-                if vc.iter().any(|s| s.name == name) || deps.iter().any(|d| d == &name) {
-                    return Err(HakuError::RecipeRecursionError(name, self.error_extra()));
-                }
-                for dep in deps {
-                    if let Some(ps) = parent {
-                        if ps.iter().any(|p| p == &dep) {
-                            return Err(HakuError::RecipeRecursionError(dep, self.error_extra()));
-                        }
+        if let Some(pos) = path.iter().position(|p| p == &name) {
+            let mut cycle: Vec<String> = path[pos..].to_vec();
+            cycle.push(name);
+            return Err(HakuError::RecipeRecursionError(cycle.join(" -> "), self.error_extra()));
+        }
+        if let Some(pos) = list.iter().position(|s| s.name == name && s.args == args) {
+            return Ok(pos);
+        }
+
+        let dep_args = self.eval_dep_args(&name, &vars, &args, &deps)?;
+        path.push(name.clone());
+        let mut dep_idx: Vec<usize> = Vec::new();
+        for (dep, dargs) in deps.into_iter().zip(dep_args) {
+            let next_s = self.find_recipe(&dep.name)?;
+            let idx = self.push_recipe(next_s.loc, dargs, list, path)?;
+            if !dep_idx.contains(&idx) {
+                dep_idx.push(idx);
+            }
+        }
+        path.pop();
+
+        list.push(RecipeItem { name, loc, vars, args, flags, deps: dep_idx, attrs });
+        Ok(list.len() - 1)
+    }
+
+    /// Evaluates arguments of recipe dependencies, e.g. `build(${ver}, "release")`. The
+    /// expressions are evaluated in the scope of the recipe that depends on them: its local
+    /// variables are initialized from `args` for the time of evaluation. A list value is
+    /// passed as a few separate arguments.
+    fn eval_dep_args(
+        &mut self,
+        name: &str,
+        vars: &[RecipeArg],
+        args: &Option<Vec<String>>,
+        deps: &[RecipeDep],
+    ) -> Result<Vec<Option<Vec<String>>>, HakuError> {
+        if deps.iter().all(|d| d.args.is_none()) {
+            return Ok(vec![None; deps.len()]);
+        }
+        let saved = mem::take(&mut self.varmgr.recipe_vars);
+        let free = args.clone().unwrap_or_else(|| self.varmgr.free.clone());
+        let res = self.bind_recipe_args(name, vars, &free).and_then(|_| {
+            let mut res = Vec::new();
+            for dep in deps.iter() {
+                let ops = match dep.args {
+                    None => {
+                        res.push(None);
+                        continue;
                     }
-                    if let Some(fnd) = found {
-                        if fnd.iter().any(|f| f.name == dep) {
-                            return Err(HakuError::RecipeRecursionError(dep, self.error_extra()));
-                        }
+                    Some(ref ops) => ops,
+                };
+                let mut vals = Vec::new();
+                for op in ops.iter() {
+                    match self.exec_op(op)? {
+                        VarValue::List(v) => vals.extend(v.iter().map(|it| it.to_string())),
+                        v => vals.push(v.to_string()),
                     }
-                    let next_s = self.find_recipe(&dep)?;
-                    parents.push(name.clone());
-                    let mut slist = self.push_recipe(next_s.loc, Some(&vc), Some(&parents))?;
-                    vc.append(&mut slist);
                 }
-                sec_item.name = name;
-                sec_item.loc = loc;
-                sec_item.vars = vars;
-                sec_item.flags = flags;
+                output!(self.opts.verbosity, 2, "Dependency {} arguments: {:?}", dep.name, vals);
+                res.push(Some(vals));
             }
-            _ => unreachable!(),
-        }
-        vc.push(sec_item);
-        Ok(vc)
+            Ok(res)
+        });
+        self.varmgr.recipe_vars = saved;
+        res
     }
 
     /// Runs a given recipe. First, it runs all recipe dependencies recursively. Second,
@@ -775,43 +1395,352 @@ impl Engine {
         output!(self.opts.verbosity, 2, "Start recipe [{}:{}]", loc.file, loc.line);
         self.real_line = loc.script_line;
         self.file_idx = loc.file;
-        let sec = self.push_recipe(loc, None, None)?;
+        let mut sec: Vec<RecipeItem> = Vec::new();
+        self.push_recipe(loc, None, &mut sec, &mut Vec::new())?;
         output!(self.opts.verbosity, 2, "recipe call stack: {:?}", sec);
-        let mut idx = 0;
-        while idx < sec.len() {
-            let now = Instant::now();
-            let op = &sec[idx];
-            output!(self.opts.verbosity, 1, "Starting recipe: {}", op.name);
-            self.enter_recipe(op);
-            self.exec_from(op.loc.file, op.loc.line + 1, op.flags)?;
+        // the recipe itself always runs, its dependencies run only if they have not
+        // been executed yet by previous recipes
+        let last = sec.len() - 1;
+        let done: Vec<bool> =
+            sec.iter().enumerate().map(|(idx, op)| idx != last && self.finished.contains(&op.job_id())).collect();
+        if self.opts.jobs > 1 && sec.len() > 1 {
+            self.exec_recipes_parallel(&sec, &done)?;
+        } else {
+            for (op, skip) in sec.iter().zip(done.iter()) {
+                if *skip {
+                    output!(self.opts.verbosity, 1, "Recipe {} has been already executed", op.job_name());
+                    continue;
+                }
+                self.exec_recipe_item(op)?;
+            }
+        }
+        for op in sec.iter() {
+            let job = op.job_id();
+            if !self.finished.contains(&job) {
+                self.finished.push(job);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the body of a single recipe without its dependencies
+    fn exec_recipe_item(&mut self, op: &RecipeItem) -> Result<(), HakuError> {
+        let now = Instant::now();
+        output!(self.opts.verbosity, 1, "Starting recipe: {}", op.name);
+        self.enter_recipe(op)?;
+        if self.recipe_up_to_date(op) {
+            output!(self.opts.verbosity, 1, "Recipe {} is up to date, skipping", op.name);
             self.leave_recipe();
-            let dur = now.elapsed();
-            if self.opts.show_time {
-                println!("Section {} finished in {}", op.name, human_duration(dur));
-            } else {
-                output!(self.opts.verbosity, 1, "Section {} finished in {}", op.name, human_duration(dur));
+            return Ok(());
+        }
+        let hash = self.recipe_hash(op);
+        if let Some(ref h) = hash {
+            if cache::is_cached(&self.cache_dir, &op.name, h) {
+                output!(self.opts.verbosity, 1, "Recipe {} inputs are unchanged, skipping", op.name);
+                self.leave_recipe();
+                return Ok(());
             }
-            idx += 1;
+        }
+        self.cur_recipe = op.job_name();
+        self.recipe_flags = op.flags;
+        self.out_mode = op.attrs.output.unwrap_or(self.opts.output);
+        self.recipe_deadline = op.attrs.timeout.map(|d| Instant::now() + d);
+        self.recipe_retry = op.attrs.retry;
+        let res = self.exec_from(op.loc.file, op.loc.line + 1, op.flags);
+        self.cur_recipe.clear();
+        self.recipe_flags = 0;
+        self.out_mode = OutputMode::Plain;
+        self.recipe_deadline = None;
+        self.recipe_retry = None;
+        self.pending_attrs = LineAttrs::default();
+        self.line_attrs = LineAttrs::default();
+        self.exec_recipe_hooks(op, res)?;
+        if let Some(h) = hash {
+            if let Err(e) = cache::store(&self.cache_dir, &op.name, &h) {
+                eprintln!("Failed to save cache for recipe {}: {}", op.name, e);
+            }
+        }
+        self.leave_recipe();
+        let dur = now.elapsed();
+        if self.opts.show_time {
+            println!("Section {} finished in {}", op.name, human_duration(dur));
+        } else {
+            output!(self.opts.verbosity, 1, "Section {} finished in {}", op.name, human_duration(dur));
         }
         Ok(())
     }
 
+    /// Runs recipes listed in `on_failure`(only if the recipe body has failed) and `finally`
+    /// attributes of a recipe after its body is done. Before running them, the engine sets
+    /// variables `error_recipe`, `error_msg`, and `error_code` (empty values and `0` if the
+    /// body has succeeded). The original error of the body is returned even if any of
+    /// the hooks fails.
+    fn exec_recipe_hooks(&mut self, op: &RecipeItem, res: Result<(), HakuError>) -> Result<(), HakuError> {
+        let mut hooks: Vec<String> = Vec::new();
+        if res.is_err() {
+            hooks.extend(op.attrs.on_failure.iter().cloned());
+        }
+        hooks.extend(op.attrs.finally.iter().cloned());
+        if hooks.is_empty() {
+            return res;
+        }
+
+        self.leave_recipe();
+        let (name, msg, code) = match res {
+            Ok(()) => (String::new(), String::new(), 0),
+            Err(ref e) => (op.name.clone(), e.to_string(), e.exit_code().unwrap_or(1)),
+        };
+        self.varmgr.set_var("error_recipe", VarValue::Str(name));
+        self.varmgr.set_var("error_msg", VarValue::Str(msg));
+        self.varmgr.set_var("error_code", VarValue::from(code));
+
+        let mut hook_err = None;
+        for hook in hooks.iter() {
+            if *hook == op.name || self.hooks_running.contains(hook) {
+                output!(self.opts.verbosity, 1, "Recipe {} is already running as a hook, skipping", hook);
+                continue;
+            }
+            output!(self.opts.verbosity, 1, "Running recipe {} after {}", hook, op.name);
+            self.hooks_running.push(hook.clone());
+            let hook_res = match self.find_recipe(hook) {
+                Ok(desc) => self.exec_recipe(desc.loc),
+                Err(e) => Err(e),
+            };
+            self.hooks_running.pop();
+            if let Err(e) = hook_res {
+                if res.is_err() || hook_err.is_some() {
+                    // the error is not returned, so show it at least
+                    eprintln!("{}", e);
+                } else {
+                    hook_err = Some(e);
+                }
+            }
+        }
+        res?;
+        match hook_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns attributes of a recipe by its location
+    fn recipe_attrs(&self, loc: &RecipeLoc) -> RecipeAttrs {
+        for r in self.recipes.iter() {
+            if r.loc.file == loc.file && r.loc.line == loc.line {
+                return r.attrs.clone();
+            }
+        }
+        RecipeAttrs::default()
+    }
+
+    /// Returns `true` if a recipe declares its outputs and they are newer than all its
+    /// sources. Paths can contain variables, including recipe local ones
+    fn recipe_up_to_date(&self, op: &RecipeItem) -> bool {
+        if op.attrs.outputs.is_empty() {
+            return false;
+        }
+        let outputs: Vec<String> = op.attrs.outputs.iter().map(|s| self.varmgr.interpolate(s, false)).collect();
+        let sources: Vec<String> = op.attrs.sources.iter().map(|s| self.varmgr.interpolate(s, false)).collect();
+        output!(self.opts.verbosity, 2, "Check outputs {:?} against sources {:?}", outputs, sources);
+        is_up_to_date(&outputs, &sources, &self.cwd)
+    }
+
+    /// Calculates a hash of everything a recipe marked with `cache` attribute depends on:
+    /// its arguments, content of its sources, its body with interpolated command lines,
+    /// and environment variables. Returns `None` if the recipe is not cached or its
+    /// sources cannot be read
+    fn recipe_hash(&self, op: &RecipeItem) -> Option<String> {
+        if !op.attrs.cache || !self.opts.use_cache {
+            return None;
+        }
+        let mut key = CacheKey::new(&op.name);
+        for v in op.vars.iter() {
+            key.add_str("arg", &self.varmgr.var(&v.name).to_flat_string());
+        }
+
+        let sources: Vec<String> = op.attrs.sources.iter().map(|s| self.varmgr.interpolate(s, false)).collect();
+        let mut files = expand_paths(&sources, &self.cwd, false)?;
+        files.sort();
+        for f in files.iter() {
+            if let Err(e) = key.add_file(f) {
+                output!(self.opts.verbosity, 1, "Failed to read {:?}: {}", f, e);
+                return None;
+            }
+        }
+
+        // only the statements of the recipe body: comments, attributes, and functions
+        // (they are global) do not change what the recipe executes
+        let ops = &self.files[op.loc.file].ops;
+        let mut idx = op.loc.line + 1;
+        while idx < ops.len() {
+            match ops[idx].op {
+                Op::Recipe(_, _, _, _) => break,
+                Op::Comment(_) | Op::DocComment(_) | Op::Feature(_, _) | Op::Attr(_, _) => {}
+                Op::Function(_, _) => match self.find_end(op.loc.file, idx + 1, "fn") {
+                    Ok(end) => {
+                        idx = end;
+                        continue;
+                    }
+                    Err(_) => break,
+                },
+                Op::Shell(_, ref cmd) | Op::Cd(_, ref cmd) => key.add_str("cmd", &self.varmgr.interpolate(cmd, true)),
+                ref o => key.add_str("op", &format!("{:?}", o)),
+            }
+            idx += 1;
+        }
+
+        let mut env: Vec<(&String, &String)> = self.varmgr.env.iter().collect();
+        env.sort();
+        for (name, val) in env {
+            key.add_str("env", &format!("{}={}", name, val));
+        }
+        for name in op.attrs.cache_env.iter() {
+            key.add_str("env", &format!("{}={}", name, env::var(name).unwrap_or_default()));
+        }
+        Some(key.finish())
+    }
+
+    /// Removes all saved recipe hashes, so all cached recipes run next time
+    pub fn clear_cache(&self) -> Result<(), HakuError> {
+        output!(self.opts.verbosity, 1, "Clearing cache {:?}", self.cache_dir);
+        cache::clear(&self.cache_dir)
+            .map_err(|e| HakuError::CacheError(self.cache_dir.to_string_lossy().to_string(), e.to_string()))
+    }
+
+    /// Creates a copy of the engine to execute a recipe in a separate thread. The copy
+    /// gets the current state of variables, so all changes made by a recipe running in
+    /// a copy are not visible to other recipes
+    fn fork(&self) -> Engine {
+        Engine {
+            files: self.files.clone(),
+            included: self.included.clone(),
+            recipes: self.recipes.clone(),
+            varmgr: self.varmgr.clone(),
+            shell: self.shell.clone(),
+            opts: self.opts.clone(),
+            cond_stack: Vec::new(),
+            real_line: self.real_line,
+            file_idx: self.file_idx,
+            cwd: self.cwd.clone(),
+            cwd_history: self.cwd_history.clone(),
+            cache_dir: self.cache_dir.clone(),
+            finished: Vec::new(),
+            funcs: self.funcs.clone(),
+            ret_val: None,
+            started: self.started,
+            cur_recipe: String::new(),
+            recipe_flags: 0,
+            out_mode: OutputMode::Plain,
+            recipe_deadline: None,
+            recipe_retry: None,
+            pending_attrs: LineAttrs::default(),
+            line_attrs: LineAttrs::default(),
+            hooks_running: Vec::new(),
+            signals_seen: self.signals_seen,
+        }
+    }
+
+    /// Runs a list of recipes as a dependency graph: a recipe starts as soon as all its
+    /// dependencies are finished, and up to `opts.jobs` recipes are executed at the same
+    /// time. After the first failure no new recipe is started, the engine waits for
+    /// the running ones and returns the first error. Recipes marked in `done` are
+    /// considered already finished.
+    fn exec_recipes_parallel(&mut self, sec: &[RecipeItem], done: &[bool]) -> Result<(), HakuError> {
+        output!(self.opts.verbosity, 2, "Run recipes in parallel, max {} jobs", self.opts.jobs);
+        let (tx, rx) = mpsc::channel();
+        let mut state: Vec<JobState> =
+            done.iter().map(|d| if *d { JobState::Done } else { JobState::Waiting }).collect();
+        let mut running = 0usize;
+        let mut first_err: Option<HakuError> = None;
+        loop {
+            if first_err.is_none() {
+                for (idx, item) in sec.iter().enumerate() {
+                    if running >= self.opts.jobs {
+                        break;
+                    }
+                    if state[idx] != JobState::Waiting || item.deps.iter().any(|d| state[*d] != JobState::Done) {
+                        continue;
+                    }
+                    let mut worker = self.fork();
+                    let item = item.clone();
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        let res = worker.exec_recipe_item(&item);
+                        // the receiver lives until all started recipes report back
+                        let _ = tx.send((idx, res));
+                    });
+                    state[idx] = JobState::Running;
+                    running += 1;
+                }
+            }
+            if running == 0 {
+                break;
+            }
+            let (idx, res) = match rx.recv() {
+                Ok(r) => r,
+                Err(e) => return Err(HakuError::InternalError(format!(": {}", e))),
+            };
+            running -= 1;
+            state[idx] = JobState::Done;
+            if let Err(e) = res {
+                output!(self.opts.verbosity, 1, "Recipe {} failed", sec[idx].name);
+                if first_err.is_none() {
+                    first_err = Some(e);
+                }
+            }
+        }
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
     /// Executes a script from a given file and the line in it. Used by run recipe function:
     /// it looks for a recipe location and then executes from that position.
     fn exec_from(&mut self, file: usize, line: usize, sec_flags: u32) -> Result<(), HakuError> {
+        let mut idx = line;
+        loop {
+            match self.exec_lines(file, idx, sec_flags) {
+                Ok(()) => return Ok(()),
+                Err(e) => idx = self.catch_error(file, e)?,
+            }
+        }
+    }
+
+    /// Executes script lines starting from line `line` until the end of a recipe or a function
+    fn exec_lines(&mut self, file: usize, line: usize, sec_flags: u32) -> Result<(), HakuError> {
         let mut idx = line;
         let l = self.files[file].ops.len();
         while idx < l {
             let op = (self.files[file].ops[idx]).clone();
             self.real_line = op.line;
             self.file_idx = file;
+            self.check_interrupt()?;
+            // attributes inside a recipe apply only to the line that follows them
             match op.op {
-                Op::Return | Op::Recipe(_, _, _, _) => return Ok(()),
+                Op::Attr(ref name, ref vals) => self.pending_attrs.apply(name, vals),
+                _ => self.line_attrs = mem::take(&mut self.pending_attrs),
+            }
+            match op.op {
+                Op::Recipe(_, _, _, _) => return Ok(()),
+                Op::Return(ops) => {
+                    if !ops.is_empty() {
+                        let v = self.exec_or_expr(&ops)?;
+                        self.ret_val = Some(v);
+                    }
+                    match self.exec_return(file)? {
+                        Some(next) => idx = next,
+                        None => return Ok(()),
+                    }
+                }
+                Op::Function(_, _) => {
+                    idx = self.find_end(file, idx + 1, "fn")?;
+                }
                 Op::Include(_, _) => return Err(HakuError::IncludeInRecipeError(self.error_extra())),
                 Op::Error(msg) => return Err(HakuError::UserError(format!("{} at line {}", msg, op.line))),
                 Op::Shell(flags, cmd) => {
                     let cmd_flags = sec_flags ^ flags;
-                    self.exec_cmd_shell(cmd_flags, &cmd)?;
+                    self.exec_cmd_retry(cmd_flags, &cmd)?;
                     idx += 1;
                 }
                 Op::EitherAssign(chk, name, ops) => {
@@ -831,7 +1760,18 @@ impl Engine {
                     idx += 1;
                 } // top level - func value is dropped
                 Op::StmtClose => {
-                    let next = self.exec_end()?;
+                    if self.cond_stack.is_empty() && self.varmgr.call_depth() > 0 {
+                        // the end of a user-defined function
+                        return Ok(());
+                    }
+                    if self.return_pending() {
+                        match self.exec_return(file)? {
+                            Some(next) => idx = next,
+                            None => return Ok(()),
+                        }
+                        continue;
+                    }
+                    let next = self.exec_end(file)?;
                     if next == 0 {
                         idx += 1
                     } else {
@@ -847,7 +1787,6 @@ impl Engine {
                     }
                 }
                 Op::While(ops) => {
-                    // must have exact 1 op
                     let ok = self.exec_while(&ops, idx)?;
                     if ok {
                         idx += 1;
@@ -863,13 +1802,23 @@ impl Engine {
                 }
                 Op::If(ops) => {
                     idx = self.exec_if(&ops, file, idx)?;
-                } // must have exact 1 op
+                }
                 Op::Else => {
                     idx = self.exec_else(file, idx)?;
                 }
                 Op::ElseIf(ops) => {
                     idx = self.exec_elseif(&ops, file, idx)?;
-                } // must have exact 1 op
+                }
+                Op::Try => {
+                    self.exec_try(idx);
+                    idx += 1;
+                }
+                Op::Catch(_) => {
+                    idx = self.exec_catch(file, idx)?;
+                }
+                Op::Finally => {
+                    idx = self.exec_finally(idx)?;
+                }
                 Op::Cd(flags, p) => {
                     let cmd_flags = sec_flags ^ flags;
                     self.exec_cd(cmd_flags, &p)?;
@@ -903,7 +1852,7 @@ impl Engine {
                         return Ok(idx + 1);
                     }
                 }
-                Op::If(_) | Op::While(_) | Op::For(_, _) => nesting += 1,
+                Op::If(_) | Op::While(_) | Op::For(_, _) | Op::Function(_, _) | Op::Try => nesting += 1,
                 _ => {}
             }
             idx += 1;
@@ -926,11 +1875,9 @@ impl Engine {
                         return Ok((true, idx + 1));
                     }
                 }
-                Op::If(_) | Op::While(_) | Op::For(_, _) => nesting += 1,
-                Op::ElseIf(_) | Op::Else => {
-                    if nesting == 1 {
-                        return Ok((false, idx));
-                    }
+                Op::If(_) | Op::While(_) | Op::For(_, _) | Op::Function(_, _) | Op::Try => nesting += 1,
+                Op::ElseIf(_) | Op::Else if nesting == 1 => {
+                    return Ok((false, idx));
                 }
                 _ => {}
             }
@@ -939,45 +1886,95 @@ impl Engine {
         Err(HakuError::NoMatchingEndError(tp.to_string(), self.error_extra()))
     }
 
-    /// Executes external command and collects its output. The command and its output are not
-    /// displayed. But if the stderr is not empty it is printed out. Before execution the
-    /// engine substitutes used variables in command line.
+    /// Executes external command and collects its standard and error output, and exit code.
+    /// The command and its output are not displayed. Non-zero exit code is not an error: it is
+    /// kept in the result, so a script can check it. Before execution the engine substitutes
+    /// used variables in command line.
     /// The output is expected to be valid UTF-8.
     ///
     /// Internal function to use by `for` or assignment statement.
     fn exec_cmd(&mut self, cmdline: &str) -> Result<ExecResult, HakuError> {
-        let cmdline = self.varmgr.interpolate(&cmdline, true);
-        let mut eres = ExecResult { code: 0, stdout: String::new() };
+        let cmdline = self.varmgr.interpolate(cmdline, true);
+        let mut eres = ExecResult { code: 0, stdout: String::new(), stderr: String::new() };
         let mut cmd = Command::new(&self.shell[0]);
         for arg in self.shell[1..].iter() {
             cmd.arg(arg);
         }
         cmd.arg(&cmdline);
         self.augment_cmd(&mut cmd);
-        let out = match cmd.output() {
-            Ok(o) => o,
+        let start = Instant::now();
+        let deadline = self.cmd_deadline(&cmdline)?;
+        if deadline.is_some() {
+            new_group(&mut cmd);
+        }
+        let out = output_with_deadline(&mut cmd, deadline);
+        self.check_interrupt()?;
+        let out = match out {
+            Ok(Some(o)) => o,
+            Ok(None) => {
+                return Err(HakuError::TimeoutError(cmdline, human_duration(start.elapsed()), self.error_extra()))
+            }
             Err(e) => return Err(HakuError::ExecFailureError(cmdline, e.to_string(), self.error_extra())),
         };
 
-        if !out.status.success() {
-            if let Ok(s) = String::from_utf8(out.stderr) {
-                eprint!("{}", s);
+        eres.code = match out.status.code() {
+            Some(code) => code,
+            None => {
+                return Err(HakuError::ExecFailureError(cmdline, "unknown exit code".to_string(), self.error_extra()))
             }
-            return Err(HakuError::ExecFailureError(
-                cmdline,
-                format!("exit code {}", out.status.code().unwrap_or(0)),
-                self.error_extra(),
-            ));
-        }
-
+        };
         if let Ok(s) = String::from_utf8(out.stdout) {
             eres.stdout = s.trim_end().to_string();
         } else {
             eres.stdout = String::from("[Non-UTF-8 Output]");
         }
+        if let Ok(s) = String::from_utf8(out.stderr) {
+            eres.stderr = s.trim_end().to_string();
+        } else {
+            eres.stderr = String::from("[Non-UTF-8 Output]");
+        }
+        if eres.code != 0 {
+            output!(self.opts.verbosity, 1, "'{}' exited with code {}", cmdline, eres.code);
+            if !eres.stderr.is_empty() {
+                eprintln!("{}", eres.stderr);
+            }
+        }
         Ok(eres)
     }
 
+    /// Returns the time a command must finish by: the earliest of the recipe and the line
+    /// time limits. Returns an error if the recipe is already out of time
+    fn cmd_deadline(&self, cmdline: &str) -> Result<Option<Instant>, HakuError> {
+        let now = Instant::now();
+        if let Some(d) = self.recipe_deadline {
+            if d <= now {
+                return Err(HakuError::TimeoutError(cmdline.to_string(), "0ms".to_string(), self.error_extra()));
+            }
+        }
+        let line = self.line_attrs.timeout.map(|d| now + d);
+        Ok(match (line, self.recipe_deadline) {
+            (Some(l), Some(r)) => Some(l.min(r)),
+            (l, r) => l.or(r),
+        })
+    }
+
+    /// Returns an error if `haku` has received SIGINT, SIGTERM, or SIGHUP since the last
+    /// check. The signal is marked processed only for this engine, so the cleanup code
+    /// (`finally` blocks and recipe hooks) runs normally until the next signal comes, and
+    /// every recipe running in parallel stops as well
+    fn check_interrupt(&mut self) -> Result<(), HakuError> {
+        if !self.opts.signals {
+            return Ok(());
+        }
+        match signal::check(&mut self.signals_seen) {
+            None => Ok(()),
+            Some(sig) => {
+                let recipe = if self.cur_recipe.is_empty() { "(script header)" } else { &self.cur_recipe };
+                Err(HakuError::Interrupted(recipe.to_string(), sig, self.error_extra()))
+            }
+        }
+    }
+
     fn augment_cmd(&self, cmd: &mut Command) {
         if !self.cwd_history.is_empty() {
             cmd.current_dir(&self.cwd);
@@ -993,10 +1990,10 @@ impl Engine {
     /// Used by script lines that are standalone shell calls, like `rm "${filename}"`
     fn exec_cmd_shell(&mut self, flags: u32, cmdline: &str) -> Result<(), HakuError> {
         let no_fail = is_flag_on(flags, FLAG_PASS);
-        let cmdline = self.varmgr.interpolate(&cmdline, true);
+        let cmdline = self.varmgr.interpolate(cmdline, true);
         output!(self.opts.verbosity, 2, "ExecShell[{}]: {}", no_fail, cmdline);
         if !is_flag_on(flags, FLAG_QUIET) {
-            println!("{}", cmdline);
+            println!("{}{}", line_prefix(self.out_mode, &self.cur_recipe, self.started), cmdline);
         }
 
         let mut cmd = Command::new(&self.shell[0]);
@@ -1005,9 +2002,25 @@ impl Engine {
         }
         cmd.arg(&cmdline);
         self.augment_cmd(&mut cmd);
-        let result = cmd.status();
+        let start = Instant::now();
+        let deadline = self.cmd_deadline(&cmdline)?;
+        if deadline.is_some() {
+            new_group(&mut cmd);
+        }
+        let result = match self.out_mode {
+            OutputMode::Plain => cmd.spawn().and_then(|mut child| wait_child(&mut child, deadline)),
+            mode => run_streamed(&mut cmd, mode, &self.cur_recipe, self.started, deadline),
+        };
+        // a command killed by a signal must not be ignored even if it is marked with `-`
+        self.check_interrupt()?;
         let st = match result {
-            Ok(exit_status) => exit_status,
+            Ok(Some(exit_status)) => exit_status,
+            Ok(None) => {
+                if is_flag_on(flags, FLAG_PASS) {
+                    return Ok(());
+                }
+                return Err(HakuError::TimeoutError(cmdline, human_duration(start.elapsed()), self.error_extra()));
+            }
             Err(e) => {
                 if is_flag_on(flags, FLAG_PASS) {
                     return Ok(());
@@ -1017,16 +2030,50 @@ impl Engine {
         };
 
         if !st.success() && !is_flag_on(flags, FLAG_PASS) {
-            let code = match st.code() {
-                None => "(unknown exit code)".to_string(),
-                Some(c) => format!("(exit code: {})", c),
+            return match st.code() {
+                Some(code) => Err(HakuError::ExecExitError(cmdline, code, self.error_extra())),
+                None => {
+                    Err(HakuError::ExecFailureError(cmdline, "(unknown exit code)".to_string(), self.error_extra()))
+                }
             };
-            return Err(HakuError::ExecFailureError(cmdline, code, self.error_extra()));
         }
 
         Ok(())
     }
 
+    /// Executes a shell command like `exec_cmd_shell` does. If the command fails, and the line
+    /// or the recipe has `retry` attribute, the command is restarted after a delay. The
+    /// result of the last attempt is returned
+    fn exec_cmd_retry(&mut self, flags: u32, cmdline: &str) -> Result<(), HakuError> {
+        let retry = match self.line_attrs.retry.or(self.recipe_retry) {
+            None => return self.exec_cmd_shell(flags, cmdline),
+            Some(r) => r,
+        };
+        // all attempts but the last must fail to be restarted, even if the command is marked with `-`
+        let strict = flags & !FLAG_PASS;
+        for attempt in 1..=retry.count {
+            match self.exec_cmd_shell(strict, cmdline) {
+                Ok(()) => return Ok(()),
+                Err(e @ HakuError::ExecExitError(_, _, _))
+                | Err(e @ HakuError::ExecFailureError(_, _, _))
+                | Err(e @ HakuError::TimeoutError(_, _, _)) => {
+                    output!(
+                        self.opts.verbosity,
+                        1,
+                        "Attempt {} of {} failed, retrying in {}: {}",
+                        attempt,
+                        retry.count + 1,
+                        human_duration(retry.backoff),
+                        e
+                    );
+                    thread::sleep(retry.backoff);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.exec_cmd_shell(flags, cmdline)
+    }
+
     /// Evaluates `ops` one by one and assigns the first non-falsy result to variable `name`.
     /// When `chk` is `true` it evaluates and assigns the new value only if the variable is
     /// falsy one(0, empty string, or shell command with non-zero exit code)
@@ -1053,28 +2100,25 @@ impl Engine {
         if chk && self.varmgr.var(name).is_true() {
             return Ok(());
         }
-        let cnt = ops.len(); // 1=simple assign, >1=logical
-        let mut val = false;
+        let v = self.exec_or_expr(ops)?;
+        self.varmgr.set_var(name, v);
+        Ok(())
+    }
+
+    /// Evaluates `ops` joined with logical OR. A single value is returned as is. For a few
+    /// values, returns 1 if any value is truthy, and 0 otherwise.
+    fn exec_or_expr(&mut self, ops: &[Op]) -> Result<VarValue, HakuError> {
+        let cnt = ops.len(); // 1=simple value, >1=logical
         for op in ops.iter() {
             let v = self.exec_op(op)?;
             if cnt == 1 {
-                // simple assign
-                self.varmgr.set_var(name, v);
-                return Ok(());
+                return Ok(v);
             }
             if v.is_true() {
-                val = true;
-                break;
+                return Ok(VarValue::Int(1));
             }
         }
-        if val {
-            let v = VarValue::Int(1);
-            self.varmgr.set_var(name, v);
-        } else {
-            let v = VarValue::Int(0);
-            self.varmgr.set_var(name, v);
-        }
-        Ok(())
+        Ok(VarValue::Int(0))
     }
 
     fn exec_assign_or(&mut self, name: &str, ops: &[Op]) -> Result<(), HakuError> {
@@ -1109,8 +2153,10 @@ impl Engine {
         }
     }
 
-    /// Executes a built-in function. First, it tries to execute as a system function(that
-    /// modifies internal engine state). If this way fails, executes the function in a common way.
+    /// Executes a function. A user-defined function with the same name goes first. If there is
+    /// no such user-defined function, it executes a built-in one: first, it tries to execute as
+    /// a system function(that modifies internal engine state). If this way fails, executes the
+    /// function in a common way.
     fn exec_func(&mut self, name: &str, ops: &[Op]) -> Result<VarValue, HakuError> {
         output!(self.opts.verbosity, 2, "Exec func {}, args: {:?}", name, ops);
         let mut args: Vec<VarValue> = Vec::new();
@@ -1118,17 +2164,49 @@ impl Engine {
             let v = self.exec_op(op)?;
             args.push(v);
         }
+        if let Some(f) = self.funcs.iter().find(|f| f.name == name).cloned() {
+            return self.exec_user_func(&f, args);
+        }
         let r = run_func(name, self, &args);
         output!(self.opts.verbosity, 3, "func {} with {} args returned {:?}", name, ops.len(), r);
         r.map_err(|s| HakuError::FunctionError(format!("{}: {}", s, self.error_extra())))
     }
 
+    /// Executes the body of a user-defined function in its own scope. The function gets its
+    /// own stack of if/for/while, so an `end` without an opened block finishes the function.
+    /// Returns the value of `return` statement or `Undefined` if the function does not return
+    /// anything.
+    fn exec_user_func(&mut self, f: &UserFunc, args: Vec<VarValue>) -> Result<VarValue, HakuError> {
+        output!(self.opts.verbosity, 2, "Exec user function {}, args: {:?}", f.name, args);
+        if args.len() > f.params.len() {
+            return Err(HakuError::FuncArgCountError(f.name.clone(), f.params.len(), args.len(), self.error_extra()));
+        }
+        if self.varmgr.call_depth() >= MAX_CALL_DEPTH {
+            return Err(HakuError::FuncRecursionError(f.name.clone(), self.error_extra()));
+        }
+        let mut args = args.into_iter();
+        let frame: Vec<(String, VarValue)> =
+            f.params.iter().map(|p| (p.clone(), args.next().unwrap_or(VarValue::Undefined))).collect();
+
+        let (real_line, file_idx) = (self.real_line, self.file_idx);
+        let cond_stack = mem::take(&mut self.cond_stack);
+        // the caller may be in `finally` part that runs before its own `return` is done
+        let caller_ret = self.ret_val.take();
+        self.varmgr.push_frame(frame);
+        let res = self.exec_from(f.file, f.line + 1, self.recipe_flags);
+        self.varmgr.pop_frame();
+        self.cond_stack = cond_stack;
+        self.real_line = real_line;
+        self.file_idx = file_idx;
+        let val = mem::replace(&mut self.ret_val, caller_ret).unwrap_or(VarValue::Undefined);
+        res.map(|_| val)
+    }
+
     /// Evaluates a condition `ops`. If it is true, starts executing `if` body. Otherwise,
     /// looks for corresponding `elseif`/`else`/`end` which comes first.
     fn exec_if(&mut self, ops: &[Op], file: usize, idx: usize) -> Result<usize, HakuError> {
         output!(self.opts.verbosity, 3, "Exec if");
-        assert!(ops.len() == 1);
-        let v = self.exec_op(&ops[0])?;
+        let v = self.exec_or_expr(ops)?;
         if v.is_true() {
             output!(self.opts.verbosity, 3, "   if == true");
             self.cond_stack.push(CondItem { line: idx, cond: Condition::If(true) });
@@ -1174,16 +2252,14 @@ impl Engine {
         if self.cond_stack.is_empty() {
             return Err(HakuError::StrayElseIfError(self.error_extra()));
         }
-        assert!(ops.len() == 1);
-
         let op = self.cond_stack[self.cond_stack.len() - 1].clone();
         match op.cond {
             Condition::If(c) => {
                 if c {
                     self.cond_stack.pop();
-                    return Ok(self.find_end(file, idx + 1, "else")?);
+                    return self.find_end(file, idx + 1, "else");
                 }
-                let v = self.exec_op(&ops[0])?;
+                let v = self.exec_or_expr(ops)?;
                 if v.is_true() {
                     let mut cnd = match self.cond_stack.pop() {
                         Some(cc) => cc,
@@ -1201,12 +2277,133 @@ impl Engine {
         }
     }
 
+    /// Starts a try block
+    fn exec_try(&mut self, idx: usize) {
+        output!(self.opts.verbosity, 3, "Exec try");
+        self.cond_stack.push(CondItem { line: idx, cond: Condition::Try(TryStage::Body, TryExit::Next) });
+    }
+
+    /// The engine reaches `catch` only if the try block body has finished without errors.
+    /// So, the function skips the catch part and continues from `finally` or `end`.
+    fn exec_catch(&mut self, file: usize, idx: usize) -> Result<usize, HakuError> {
+        output!(self.opts.verbosity, 3, "Exec catch");
+        let line = match self.cond_stack.last() {
+            Some(CondItem { line, cond: Condition::Try(TryStage::Body, _) }) => *line,
+            _ => return Err(HakuError::StrayTryPartError("CATCH".to_string(), self.error_extra())),
+        };
+        let next = self.find_try_part(file, idx + 1, false)?;
+        self.cond_stack.pop();
+        // without `finally` the block ends here
+        if let Op::Finally = self.files[file].ops[next].op {
+            self.cond_stack.push(CondItem { line, cond: Condition::Try(TryStage::Finally, TryExit::Next) });
+        }
+        Ok(next + 1)
+    }
+
+    /// Starts `finally` part of a try block after its body or catch part is done
+    fn exec_finally(&mut self, idx: usize) -> Result<usize, HakuError> {
+        output!(self.opts.verbosity, 3, "Exec finally");
+        match self.cond_stack.last_mut() {
+            Some(CondItem { cond: Condition::Try(ref mut stage, _), .. }) if *stage != TryStage::Finally => {
+                *stage = TryStage::Finally;
+                Ok(idx + 1)
+            }
+            _ => Err(HakuError::StrayTryPartError("FINALLY".to_string(), self.error_extra())),
+        }
+    }
+
+    /// Looks for the next `catch`, `finally`, or `end` of the current try block starting
+    /// from line `line`. If `skip_catch` is `true`, `catch` is skipped. Returns the line
+    /// index of the found statement.
+    fn find_try_part(&self, file: usize, line: usize, skip_catch: bool) -> Result<usize, HakuError> {
+        let mut idx = line;
+        let l = self.files[file].ops.len();
+        let mut nesting = 1;
+        while idx < l {
+            match self.files[file].ops[idx].op {
+                Op::StmtClose => {
+                    nesting -= 1;
+                    if nesting == 0 {
+                        return Ok(idx);
+                    }
+                }
+                Op::If(_) | Op::While(_) | Op::For(_, _) | Op::Function(_, _) | Op::Try => nesting += 1,
+                Op::Catch(_) if nesting == 1 && !skip_catch => return Ok(idx),
+                Op::Finally if nesting == 1 => return Ok(idx),
+                _ => {}
+            }
+            idx += 1;
+        }
+        Err(HakuError::NoMatchingEndError("try".to_string(), self.error_extra()))
+    }
+
+    /// Looks for the closest try block that can handle the error `err`. All if/while/for
+    /// blocks inside the try block are dropped. If the error happened inside try block body,
+    /// the engine continues from `catch` part. If the error happened inside catch part, or
+    /// the block does not have a catch part, the engine runs `finally` part and then returns
+    /// the error. Returns the line to continue from, or the error if there is no try block
+    /// to handle it.
+    fn catch_error(&mut self, file: usize, err: HakuError) -> Result<usize, HakuError> {
+        while let Some(item) = self.cond_stack.pop() {
+            let stage = match item.cond {
+                Condition::Try(stage, _) => stage,
+                _ => continue,
+            };
+            match stage {
+                TryStage::Body => {
+                    // an interrupted script cannot be recovered: only the cleanup code runs
+                    let skip_catch = matches!(err, HakuError::Interrupted(_, _, _));
+                    let next = self.find_try_part(file, item.line + 1, skip_catch)?;
+                    match self.files[file].ops[next].op {
+                        Op::Catch(ref names) => {
+                            output!(self.opts.verbosity, 2, "Caught error: {}", err);
+                            let names = names.clone();
+                            if let Some(name) = names.first() {
+                                self.varmgr.set_var(name, VarValue::Str(err.to_string()));
+                            }
+                            if let Some(name) = names.get(1) {
+                                self.varmgr.set_var(name, VarValue::Int(i64::from(err.exit_code().unwrap_or(1))));
+                            }
+                            self.cond_stack.push(CondItem {
+                                line: item.line,
+                                cond: Condition::Try(TryStage::Catch, TryExit::Next),
+                            });
+                            return Ok(next + 1);
+                        }
+                        Op::Finally => {
+                            self.cond_stack.push(CondItem {
+                                line: item.line,
+                                cond: Condition::Try(TryStage::Finally, TryExit::Error(err)),
+                            });
+                            return Ok(next + 1);
+                        }
+                        _ if skip_catch => {}
+                        // try without catch and finally just ignores the error
+                        _ => return Ok(next + 1),
+                    }
+                }
+                TryStage::Catch => {
+                    let next = self.find_try_part(file, item.line + 1, true)?;
+                    if let Op::Finally = self.files[file].ops[next].op {
+                        self.cond_stack.push(CondItem {
+                            line: item.line,
+                            cond: Condition::Try(TryStage::Finally, TryExit::Error(err)),
+                        });
+                        return Ok(next + 1);
+                    }
+                }
+                // an error inside `finally` replaces the previous one
+                TryStage::Finally => {}
+            }
+        }
+        Err(err)
+    }
+
     /// Evaluates a condition `ops`. If it is true, starts executing `while` body. Otherwise,
     /// looks for corresponding `end`.
     fn exec_while(&mut self, ops: &[Op], idx: usize) -> Result<bool, HakuError> {
         output!(self.opts.verbosity, 3, "Exec while {:?}", ops);
-        assert!(ops.len() == 1);
-        let v = self.exec_op(&ops[0])?;
+        let v = self.exec_or_expr(ops)?;
         if v.is_true() {
             let lst: Vec<Op> = ops.to_vec();
             self.cond_stack.push(CondItem { line: idx, cond: Condition::While(lst) });
@@ -1216,17 +2413,25 @@ impl Engine {
 
     /// Processed `end` statement. For `if` it just continues execution. For `while` and `for`
     /// it checks the current loop condition: if `true`, it start the next loop cycle;
-    /// if `false`, it continues execution for the next line.
-    fn exec_end(&mut self) -> Result<usize, HakuError> {
+    /// if `false`, it continues execution for the next line. For `try` it finishes `break`,
+    /// `continue`, or an error that has left the block before its `finally` part.
+    fn exec_end(&mut self, file: usize) -> Result<usize, HakuError> {
         output!(self.opts.verbosity, 3, "Exec end");
         if let Some(op) = self.cond_stack.pop() {
             output!(self.opts.verbosity, 3, "END OP >> {:?}", op);
             match op.cond {
                 Condition::If(_) => Ok(0), // just continue
+                Condition::Try(_, exit) => match exit {
+                    TryExit::Next => Ok(0),
+                    // an error that was not caught by the block goes further after `finally`
+                    TryExit::Error(e) => Err(e),
+                    TryExit::Break => self.exec_break(file),
+                    TryExit::Continue => self.exec_continue(file),
+                    // the caller checks for a pending `return` before calling the function
+                    TryExit::Return => Err(HakuError::InternalError(self.error_extra())),
+                },
                 Condition::While(ref ops) => {
-                    // should be 1 operation
-                    assert!(ops.len() == 1);
-                    let val = self.exec_op(&ops[0])?;
+                    let val = self.exec_or_expr(ops)?;
                     if val.is_true() {
                         let ln = op.line + 1;
                         self.cond_stack.push(op);
@@ -1240,9 +2445,8 @@ impl Engine {
                     if vals.is_empty() {
                         return Ok(0);
                     }
-                    let val = vals[0].clone();
-                    vals.remove(0);
-                    self.varmgr.set_var(&var, VarValue::Str(val));
+                    let val = vals.remove(0);
+                    self.varmgr.set_var(&var, val);
                     self.cond_stack.push(CondItem { line: op.line, cond: Condition::ForList(var, vals) });
                     Ok(op.line + 1)
                 }
@@ -1262,27 +2466,77 @@ impl Engine {
         }
     }
 
-    /// Breaks the current `for` or `while`.
+    /// Called when `break`, `continue`, or `return` leaves a block `item`. If the block is
+    /// a try block, and its `finally` part has not started yet, the engine runs `finally` part
+    /// and does `exit` at its `end`. Returns the line to continue from, or `None` if there is
+    /// nothing to run.
+    fn leave_try(&mut self, file: usize, item: &CondItem, exit: TryExit) -> Result<Option<usize>, HakuError> {
+        match item.cond {
+            Condition::Try(TryStage::Body, _) | Condition::Try(TryStage::Catch, _) => {}
+            _ => return Ok(None),
+        }
+        let next = self.find_try_part(file, item.line + 1, true)?;
+        if let Op::Finally = self.files[file].ops[next].op {
+            self.cond_stack.push(CondItem { line: item.line, cond: Condition::Try(TryStage::Finally, exit) });
+            return Ok(Some(next + 1));
+        }
+        Ok(None)
+    }
+
+    /// Leaves the current function, recipe, or script header. All open blocks are dropped.
+    /// If `return` is inside a try block with `finally` part, the engine runs `finally` part
+    /// first and continues leaving at its `end`. Returns the line to continue from, or `None`
+    /// if the engine must stop executing lines.
+    fn exec_return(&mut self, file: usize) -> Result<Option<usize>, HakuError> {
+        output!(self.opts.verbosity, 3, "Exec return");
+        while let Some(cnd) = self.cond_stack.pop() {
+            if let Some(next) = self.leave_try(file, &cnd, TryExit::Return)? {
+                return Ok(Some(next));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns `true` if `end` of a try block finishes `finally` part that was started by
+    /// `return`
+    fn return_pending(&self) -> bool {
+        matches!(self.cond_stack.last(), Some(CondItem { cond: Condition::Try(_, TryExit::Return), .. }))
+    }
+
+    /// Breaks the current `for` or `while`. If `break` is inside a try block with `finally`
+    /// part, the engine runs `finally` part first and breaks the loop at its `end`.
     fn exec_break(&mut self, file: usize) -> Result<usize, HakuError> {
         output!(self.opts.verbosity, 3, "Exec break");
         while let Some(cnd) = self.cond_stack.pop() {
             match cnd.cond {
                 Condition::If(_) => continue,
+                Condition::Try(_, _) => {
+                    if let Some(next) = self.leave_try(file, &cnd, TryExit::Break)? {
+                        return Ok(next);
+                    }
+                }
                 _ => {
-                    return Ok(self.find_end(file, cnd.line + 1, "break")?);
+                    return self.find_end(file, cnd.line + 1, "break");
                 }
             }
         }
         Err(HakuError::NoMatchingForWhileError(self.error_extra()))
     }
 
-    /// Restarts the current `for` or `while` cycle from its first line.
+    /// Restarts the current `for` or `while` cycle from its first line. If `continue` is
+    /// inside a try block with `finally` part, the engine runs `finally` part first and
+    /// restarts the cycle at its `end`.
     fn exec_continue(&mut self, file: usize) -> Result<usize, HakuError> {
         output!(self.opts.verbosity, 3, "Exec continue");
         let mut next: usize = usize::MAX;
         while let Some(cnd) = self.cond_stack.pop() {
             match cnd.cond {
                 Condition::If(_) => continue,
+                Condition::Try(_, _) => {
+                    if let Some(fin) = self.leave_try(file, &cnd, TryExit::Continue)? {
+                        return Ok(fin);
+                    }
+                }
                 _ => {
                     next = self.find_end(file, cnd.line + 1, "continue")?;
                     self.cond_stack.push(cnd);
@@ -1321,43 +2575,26 @@ impl Engine {
             Seq::Str(s) => {
                 output!(self.opts.verbosity, 3, "  FOR: whitespace-delimited string {}", s);
                 let s = self.varmgr.interpolate(&s, false);
-                let mut v: Vec<String> = if s.find('\n').is_some() {
-                    s.trim_end().split('\n').map(|s| s.trim_end().to_string()).collect()
+                let v: Vec<VarValue> = if s.find('\n').is_some() {
+                    s.trim_end().split('\n').map(|s| VarValue::Str(s.trim_end().to_string())).collect()
                 } else {
-                    s.split_ascii_whitespace().map(|s| s.to_string()).collect()
+                    s.split_ascii_whitespace().map(|s| VarValue::Str(s.to_string())).collect()
                 };
                 output!(self.opts.verbosity, 3, "   FOR whitespace: {:?}", v);
-                if v.is_empty() {
-                    return Ok(false);
-                }
-                self.varmgr.set_var(name, VarValue::Str(v[0].clone()));
-                v.remove(0);
-                self.cond_stack.push(CondItem { line: idx, cond: Condition::ForList(name.to_string(), v) });
-                return Ok(true);
+                return Ok(self.start_for_list(name, idx, v));
             }
             Seq::Idents(ids) => {
                 output!(self.opts.verbosity, 3, "  FOR idents: {:?}", ids);
-                if ids.is_empty() {
-                    return Ok(false);
-                }
-                let first = self.varmgr.interpolate(&ids[0], false);
-                self.varmgr.set_var(name, VarValue::Str(first));
-                let v: Vec<String> = ids.iter().skip(1).map(|s| self.varmgr.interpolate(s, false)).collect();
-                self.cond_stack.push(CondItem { line: idx, cond: Condition::ForList(name.to_string(), v) });
-                return Ok(true);
+                let v: Vec<VarValue> = ids.iter().map(|s| VarValue::Str(self.varmgr.interpolate(s, false))).collect();
+                return Ok(self.start_for_list(name, idx, v));
             }
             Seq::Exec(s) => match self.exec_cmd(&s) {
                 Ok(res) => {
                     if res.code == 0 {
-                        let mut v: Vec<String> = res.stdout.lines().map(|s| s.trim_end().to_string()).collect();
+                        let v: Vec<VarValue> =
+                            res.stdout.lines().map(|s| VarValue::Str(s.trim_end().to_string())).collect();
                         output!(self.opts.verbosity, 3, "   FOR exec: {:?}", v);
-                        if v.is_empty() {
-                            return Ok(false);
-                        }
-                        self.varmgr.set_var(name, VarValue::Str(v[0].clone()));
-                        v.remove(0);
-                        self.cond_stack.push(CondItem { line: idx, cond: Condition::ForList(name.to_string(), v) });
-                        return Ok(true);
+                        return Ok(self.start_for_list(name, idx, v));
                     } else {
                         output!(self.opts.verbosity, 3, "   FOR exec: FAILURE");
                     };
@@ -1369,63 +2606,54 @@ impl Engine {
             Seq::Var(s) => {
                 output!(self.opts.verbosity, 3, "   FOR var ${}", s);
                 let val = self.varmgr.var(&s);
-                match val {
-                    VarValue::Undefined => {}
-                    VarValue::Int(start) => {
-                        output!(self.opts.verbosity, 3, "   FOR var int ${} = {}", s, start);
-                        self.varmgr.set_var(name, VarValue::Int(start));
-                        self.cond_stack
-                            .push(CondItem { line: idx, cond: Condition::ForInt(name.to_string(), start, start, 1) });
-                        return Ok(true);
-                    }
-                    VarValue::List(vc) => {
-                        output!(self.opts.verbosity, 3, "   FOR var list ${} = {:?}", s, vc);
-                        let mut first = true;
-                        let mut v: Vec<String> = Vec::new();
-                        for s in vc.iter() {
-                            if first {
-                                self.varmgr.set_var(name, VarValue::from(s.to_string()));
-                                first = false;
-                            } else {
-                                v.push(s.to_string());
-                            }
-                        }
-                        self.cond_stack.push(CondItem { line: idx, cond: Condition::ForList(name.to_string(), v) });
-                        return Ok(true);
-                    }
-                    VarValue::Exec(ex) => {
-                        output!(self.opts.verbosity, 3, "   FOR var exec ${} = {:?}", s, ex);
-                        if ex.code != 0 || ex.stdout.is_empty() {
-                            return Ok(false);
-                        }
-                        let mut v: Vec<String> =
-                            ex.stdout.trim_end().split('\n').map(|s| s.trim_end().to_string()).collect();
-                        self.varmgr.set_var(name, VarValue::Str(v[0].clone()));
-                        v.remove(0);
-                        self.cond_stack.push(CondItem { line: idx, cond: Condition::ForList(name.to_string(), v) });
-                        return Ok(true);
-                    }
-                    VarValue::Str(st) => {
-                        output!(self.opts.verbosity, 3, "   FOR var str ${} = {}", s, st);
-                        if st.is_empty() {
-                            return Ok(false);
-                        }
-                        let mut v: Vec<String> = if st.find('\n').is_some() {
-                            st.trim_end().split('\n').map(|s| s.trim_end().to_string()).collect()
-                        } else {
-                            st.trim_end().split_whitespace().map(|s| s.to_string()).collect()
-                        };
-                        self.varmgr.set_var(name, VarValue::Str(v[0].clone()));
-                        v.remove(0);
-                        self.cond_stack.push(CondItem { line: idx, cond: Condition::ForList(name.to_string(), v) });
-                        return Ok(true);
-                    }
-                }
+                return Ok(self.start_for_value(name, idx, val));
+            }
+            Seq::Value(op) => {
+                output!(self.opts.verbosity, 3, "   FOR value {:?}", op);
+                let val = self.exec_op(&op)?;
+                return Ok(self.start_for_value(name, idx, val));
             }
         }
         Ok(false)
     }
 
+    /// Starts a `for` loop over a value of a variable or an expression:
+    ///
+    /// * list - runs through list items
+    /// * map - runs through map keys
+    /// * result of shell execution - runs through lines of its output
+    /// * string - runs through lines, or through words if the string is a single line
+    /// * integer or boolean - runs once
+    ///
+    /// Returns `false` if there is nothing to iterate
+    fn start_for_value(&mut self, name: &str, idx: usize, val: VarValue) -> bool {
+        match val {
+            VarValue::Int(start) => {
+                output!(self.opts.verbosity, 3, "   FOR value int {}", start);
+                self.varmgr.set_var(name, VarValue::Int(start));
+                self.cond_stack
+                    .push(CondItem { line: idx, cond: Condition::ForInt(name.to_string(), start, start, 1) });
+                true
+            }
+            _ => {
+                output!(self.opts.verbosity, 3, "   FOR value {:?}", val);
+                self.start_for_list(name, idx, val.to_list())
+            }
+        }
+    }
+
+    /// Starts a `for` loop over a list of values: assigns the first value to the loop variable
+    /// and keeps the rest for next cycles. Returns `false` if the list is empty
+    fn start_for_list(&mut self, name: &str, idx: usize, mut v: Vec<VarValue>) -> bool {
+        if v.is_empty() {
+            return false;
+        }
+        let first = v.remove(0);
+        self.varmgr.set_var(name, first);
+        self.cond_stack.push(CondItem { line: idx, cond: Condition::ForList(name.to_string(), v) });
+        true
+    }
+
     fn interpolate_path(&self, path: &str) -> String {
         if path != "~" && !path.starts_with("~/") && !path.starts_with("~\\") {
             return path.to_string();
@@ -1451,7 +2679,7 @@ impl Engine {
     /// for the next `elseif`/`else`/`end` which comes first.
     fn exec_cd(&mut self, flags: u32, path: &str) -> Result<(), HakuError> {
         output!(self.opts.verbosity, 3, "Exec cd");
-        let path = self.varmgr.interpolate(&path, true);
+        let path = self.varmgr.interpolate(path, true);
         let path = self.interpolate_path(&path);
         if !is_flag_on(flags, FLAG_QUIET) {
             println!("cd {}", path);
@@ -1489,7 +2717,9 @@ impl Engine {
     /// Compares two variables. Returns 1 if condition is true, and 0 otherwise.
     fn exec_compare(&mut self, cmp_op: &str, args: &[Op]) -> Result<VarValue, HakuError> {
         // compare always get 2 arguments
-        assert!(args.len() == 2);
+        if args.len() != 2 {
+            return Err(HakuError::InternalError(self.error_extra()));
+        }
         let v1 = self.exec_op(&args[0])?;
         let v2 = self.exec_op(&args[1])?;
         if v1.cmp(&v2, cmp_op) {
@@ -1499,18 +2729,67 @@ impl Engine {
         }
     }
 
+    /// Evaluates an arithmetic operation. Both values are converted to integers. Integer
+    /// overflow and division by zero are errors.
+    fn exec_arith(&mut self, arith_op: &str, args: &[Op]) -> Result<VarValue, HakuError> {
+        if args.len() != 2 {
+            return Err(HakuError::InternalError(self.error_extra()));
+        }
+        let a = self.exec_op(&args[0])?.to_int();
+        let b = self.exec_op(&args[1])?.to_int();
+        let res = match arith_op {
+            "/" | "%" if b == 0 => return Err(HakuError::DivByZeroError(self.error_extra())),
+            "+" => a.checked_add(b),
+            "-" => a.checked_sub(b),
+            "*" => a.checked_mul(b),
+            "/" => a.checked_div(b),
+            "%" => a.checked_rem(b),
+            _ => return Err(HakuError::InternalError(self.error_extra())),
+        };
+        match res {
+            Some(v) => Ok(VarValue::Int(v)),
+            None => Err(HakuError::OverflowError(format!("{} {} {}", a, arith_op, b), self.error_extra())),
+        }
+    }
+
     /// Generic function: executes any expression value(variable, shell exec, function).
     fn exec_op(&mut self, op: &Op) -> Result<VarValue, HakuError> {
         match op {
             Op::Int(i) => Ok(VarValue::Int(*i)),
+            Op::Bool(b) => Ok(VarValue::Bool(*b)),
             Op::Str(s) => {
-                let s = self.varmgr.interpolate(&s, false);
+                let s = self.varmgr.interpolate(s, false);
                 Ok(VarValue::Str(s))
             }
+            Op::List(ops) => {
+                let mut v = Vec::new();
+                for o in ops.iter() {
+                    v.push(self.exec_op(o)?);
+                }
+                Ok(VarValue::List(v))
+            }
+            Op::Map(entries) => {
+                let mut m = BTreeMap::new();
+                for (k, o) in entries.iter() {
+                    let key = self.varmgr.interpolate(k, false);
+                    let val = self.exec_op(o)?;
+                    m.insert(key, val);
+                }
+                Ok(VarValue::Map(m))
+            }
+            Op::Index(ops) => {
+                let mut val = VarValue::Undefined;
+                for (i, o) in ops.iter().enumerate() {
+                    let v = self.exec_op(o)?;
+                    val = if i == 0 { v } else { val.item(&v) };
+                }
+                Ok(val)
+            }
             Op::Var(name) => Ok(self.varmgr.var(name)),
             Op::Exec(s) => match self.exec_cmd(s) {
+                Err(e @ HakuError::TimeoutError(_, _, _)) | Err(e @ HakuError::Interrupted(_, _, _)) => Err(e),
                 Err(_) => Ok(VarValue::Undefined),
-                Ok(er) => Ok(VarValue::Exec(er)),
+                Ok(er) => Ok(VarValue::Exec(Box::new(er))),
             },
             Op::Not(ops) => {
                 // now Not must contain only 1 op - it should be by *.pest rules
@@ -1524,8 +2803,28 @@ impl Engine {
                 }
                 unreachable!()
             }
+            Op::Neg(ops) => {
+                // now Neg must contain only 1 op - it should be by *.pest rules
+                if let Some(o) = ops.iter().next() {
+                    let i = self.exec_op(o)?.to_int();
+                    return match i.checked_neg() {
+                        Some(n) => Ok(VarValue::Int(n)),
+                        None => Err(HakuError::OverflowError(format!("-({})", i), self.error_extra())),
+                    };
+                }
+                unreachable!()
+            }
+            Op::Arith(arith_op, ops) => self.exec_arith(arith_op, ops),
+            Op::Concat(ops) => {
+                let mut s = String::new();
+                for o in ops.iter() {
+                    s += &self.exec_op(o)?.to_flat_string();
+                }
+                Ok(VarValue::Str(s))
+            }
+            Op::OrExpr(ops) => self.exec_or_expr(ops),
             Op::AndExpr(ops) => self.exec_and_expr(ops),
-            Op::Func(name, ops) => self.exec_func(&name, &ops),
+            Op::Func(name, ops) => self.exec_func(name, ops),
             Op::Compare(cmp_op, ops) => self.exec_compare(cmp_op, ops),
             _ => unreachable!(),
         }
@@ -1545,6 +2844,7 @@ impl Engine {
             };
         }
 
+        let _signals = if self.opts.signals { Some(signal::pause()) } else { None };
         let mut _input = String::new();
         match io::stdin().read_line(&mut _input) {
             Ok(_) => Ok(()),
@@ -1554,32 +2854,64 @@ impl Engine {
 
     /// Executed before staring the next recipe. It does all preparations, like recipe
     /// local variable initialization.
-    fn enter_recipe(&mut self, recipe: &RecipeItem) {
-        output!(self.opts.verbosity, 2, "enter recipe. Vars {:?}, Free {:?}", recipe.vars, self.varmgr.free);
-        if recipe.vars.is_empty() || self.varmgr.free.is_empty() {
-            return;
+    fn enter_recipe(&mut self, recipe: &RecipeItem) -> Result<(), HakuError> {
+        let free = recipe.args.clone().unwrap_or_else(|| self.varmgr.free.clone());
+        self.bind_recipe_args(&recipe.name, &recipe.vars, &free)
+    }
+
+    /// Initializes recipe local variables.
+    ///
+    /// Free arguments in the form `--name=value` are assigned to the recipe variables with
+    /// the same names. The rest arguments fill the remaining variables in order. Variables
+    /// that did not get any value use their default values.
+    fn bind_recipe_args(&mut self, name: &str, vars: &[RecipeArg], free: &[String]) -> Result<(), HakuError> {
+        output!(self.opts.verbosity, 2, "enter recipe. Vars {:?}, Free {:?}", vars, free);
+        if vars.is_empty() {
+            return Ok(());
         }
 
-        // init recipe vars
-        let mut idx = 0usize;
-        for v in recipe.vars.iter() {
-            if v.starts_with('+') {
-                let nm = v.trim_start_matches('+');
-                let mut out = Vec::new();
-                while idx < self.varmgr.free.len() {
-                    out.push(self.varmgr.free[idx].clone());
-                    idx += 1;
-                }
-                self.varmgr.set_recipe_var(nm, VarValue::List(out));
-                return;
-            } else {
-                self.varmgr.set_recipe_var(v, VarValue::Str(self.varmgr.free[idx].clone()));
-                idx += 1;
-                if idx >= self.varmgr.free.len() {
-                    return;
+        // split free arguments into named and positional ones
+        let mut named: Vec<(String, String)> = Vec::new();
+        let mut positional: Vec<String> = Vec::new();
+        for arg in free.iter() {
+            if let Some(pair) = arg.strip_prefix("--") {
+                if let Some((nm, val)) = pair.split_once('=') {
+                    if vars.iter().any(|v| !v.list && v.name == nm) {
+                        named.push((nm.to_string(), val.to_string()));
+                        continue;
+                    }
                 }
             }
+            positional.push(arg.clone());
         }
+
+        // init recipe vars
+        let mut missing = Vec::new();
+        let mut pos = positional.into_iter();
+        for v in vars.iter() {
+            if v.list {
+                self.varmgr.set_recipe_var(&v.name, VarValue::List(pos.by_ref().map(VarValue::Str).collect()));
+                break;
+            }
+            let val = match named.iter().rev().find(|(nm, _)| nm == &v.name) {
+                Some((_, val)) => Some(val.clone()),
+                None => pos.next(),
+            };
+            match val {
+                Some(val) => self.varmgr.set_recipe_var(&v.name, VarValue::Str(val)),
+                None => match v.default {
+                    Some(ref d) => {
+                        let val = self.varmgr.interpolate(d, false);
+                        self.varmgr.set_recipe_var(&v.name, VarValue::Str(val));
+                    }
+                    None => missing.push(v.name.clone()),
+                },
+            }
+        }
+        if !missing.is_empty() {
+            return Err(HakuError::RecipeArgMissingError(name.to_string(), missing.join(", ")));
+        }
+        Ok(())
     }
 
     /// When the last line of a recipe is done, it cleans up temporary resources allocated
@@ -1616,14 +2948,645 @@ mod vm_test {
         );
     }
 
+    #[test]
+    fn recipe_deps() {
+        let opts = RunOpts::new();
+        let mut vm = Engine::new(opts);
+        let res = vm.load_from_str("a:\nb: a\nc:\nall: b c");
+        assert!(res.is_ok());
+        let loc = vm.find_recipe("all").unwrap().loc;
+        let mut sec = Vec::new();
+        let idx = vm.push_recipe(loc, None, &mut sec, &mut Vec::new()).unwrap();
+        assert_eq!(idx, 3);
+        let names: Vec<&str> = sec.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "c", "all"]);
+        assert!(sec[0].deps.is_empty());
+        assert_eq!(sec[1].deps, vec![0]);
+        assert!(sec[2].deps.is_empty());
+        assert_eq!(sec[3].deps, vec![1, 2]);
+    }
+
+    #[test]
+    fn recipe_shared_deps() {
+        let opts = RunOpts::new();
+        let mut vm = Engine::new(opts);
+        let res = vm.load_from_str("gen:\nbuild: gen\ntest: gen build\nall: build test gen");
+        assert!(res.is_ok());
+        let loc = vm.find_recipe("all").unwrap().loc;
+        let mut sec = Vec::new();
+        vm.push_recipe(loc, None, &mut sec, &mut Vec::new()).unwrap();
+        let names: Vec<&str> = sec.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["gen", "build", "test", "all"]);
+        assert_eq!(sec[2].deps, vec![0, 1]);
+        assert_eq!(sec[3].deps, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn recipe_cycle() {
+        let opts = RunOpts::new();
+        let mut vm = Engine::new(opts);
+        let res = vm.load_from_str("a: c\nb: a\nc: b\nall: a");
+        assert!(res.is_ok());
+        let loc = vm.find_recipe("all").unwrap().loc;
+        let mut sec = Vec::new();
+        match vm.push_recipe(loc, None, &mut sec, &mut Vec::new()) {
+            Err(HakuError::RecipeRecursionError(cycle, _)) => assert_eq!(cycle, "a -> c -> b -> a"),
+            r => panic!("cycle not detected: {:?}", r),
+        }
+        let loc = vm.find_recipe("b").unwrap().loc;
+        assert!(vm.push_recipe(loc, None, &mut Vec::new(), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn recipe_attrs() {
+        let opts = RunOpts::new();
+        let mut vm = Engine::new(opts);
+        let src = "#[sources(\"src/*.rs\", Cargo.toml), outputs('target/app')]\nbuild:\n\
+            #[os(nonexistent)]\nnone:\n#[outputs(\"doc\")]\n#[cache(CC, CFLAGS)]\ndoc:\nplain:\n\
+            #[output(time), outputs(log)]\nlogged:";
+        let res = vm.load_from_str(src);
+        assert!(res.is_ok());
+        let build = vm.find_recipe("build").unwrap();
+        assert_eq!(build.attrs.sources, vec!["src/*.rs".to_string(), "Cargo.toml".to_string()]);
+        assert_eq!(build.attrs.outputs, vec!["target/app".to_string()]);
+        assert!(!build.attrs.cache);
+        let doc = vm.find_recipe("doc").unwrap();
+        assert_eq!(doc.attrs.outputs, vec!["doc".to_string()]);
+        assert!(doc.attrs.sources.is_empty());
+        assert!(doc.attrs.cache);
+        assert_eq!(doc.attrs.cache_env, vec!["CC".to_string(), "CFLAGS".to_string()]);
+        let plain = vm.find_recipe("plain").unwrap();
+        assert!(plain.attrs.outputs.is_empty());
+        assert!(!plain.attrs.cache);
+        assert_eq!(plain.attrs.output, None);
+        let logged = vm.find_recipe("logged").unwrap();
+        assert_eq!(logged.attrs.output, Some(OutputMode::Time));
+        assert_eq!(logged.attrs.outputs, vec!["log".to_string()]);
+
+        for src in ["#[output(bogus)]\na:", "#[output]\na:", "#[output(prefix, time)]\na:"] {
+            let mut vm = Engine::new(RunOpts::new());
+            assert!(vm.load_from_str(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn recipe_hash_body() {
+        let hash = |src: &str| {
+            let mut vm = Engine::new(RunOpts::new());
+            assert!(vm.load_from_str(src).is_ok());
+            let loc = vm.find_recipe("a").unwrap().loc;
+            let mut sec = Vec::new();
+            let idx = vm.push_recipe(loc, None, &mut sec, &mut Vec::new()).unwrap();
+            vm.recipe_hash(&sec[idx]).unwrap()
+        };
+        let base = hash("#[cache]\na:\necho a\nb:\necho b");
+        assert_eq!(base, hash("#[cache]\na:\n# comment\necho a\n// comment\nb:\necho b"));
+        assert_eq!(base, hash("#[cache]\na:\necho a\n## about b\n#[os(linux)]\nb:\necho b"));
+        assert_eq!(base, hash("#[cache]\na:\necho a\nfn f()\necho f\nend\nb:\necho b"));
+        assert_eq!(base, hash("#[cache]\na:\necho a\nb:\necho changed"));
+        assert_ne!(base, hash("#[cache]\na:\necho changed\nb:\necho b"));
+        assert_ne!(base, hash("#[cache]\na:\necho a\nfn f()\nend\necho more\nb:\necho b"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn prefixed_output() {
+        let mut vm = Engine::new(RunOpts::new());
+        let src = "#[output(prefix)]\nok:\n@true\nv = `echo captured`\n#[output(time)]\nfail:\n@exit 3";
+        assert!(vm.load_from_str(src).is_ok());
+        assert!(vm.run_recipe("ok").is_ok());
+        // commands in assignments are captured, not streamed
+        assert_eq!(vm.varmgr.var("v").to_string(), "captured");
+        match vm.run_recipe("fail") {
+            Err(e) => assert_eq!(e.exit_code(), Some(3)),
+            Ok(_) => panic!("recipe must fail"),
+        }
+    }
+
+    #[test]
+    fn recipe_list() {
+        let opts = RunOpts::new();
+        let mut vm = Engine::new(opts);
+        let src = "cnt = 0\ngen:\ncnt = inc($cnt)\nbuild v: gen\nres = \"${res}b${v}\"\n\
+            test v: gen build\nres = \"${res}t${v}\"";
+        let res = vm.load_from_str(src);
+        assert!(res.is_ok());
+        let calls = vec![
+            RecipeCall { name: "build".to_string(), args: vec!["1".to_string()] },
+            RecipeCall { name: "test".to_string(), args: vec!["2".to_string()] },
+        ];
+        assert!(vm.run_recipes(&calls).is_ok());
+        assert_eq!(vm.varmgr.var("cnt"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("res"), VarValue::from("b1t2"));
+
+        let calls = vec![RecipeCall { name: "unknown".to_string(), args: Vec::new() }];
+        assert!(vm.run_recipes(&calls).is_err());
+    }
+
+    #[test]
+    fn recipe_args() {
+        let opts = RunOpts::new();
+        let mut vm = Engine::new(opts);
+        let src = "dir = \"out\"\ndeploy env=\"staging\" dst=\"${dir}/bin\" +targets:\n\
+            res = \"${env}:${dst}:${targets}\"\nneed a b=5:\nres = \"${a}${b}\"";
+        let res = vm.load_from_str(src);
+        assert!(res.is_ok());
+        let rec = vm.recipes().iter().find(|r| r.name == "deploy").unwrap();
+        assert_eq!(rec.vars[0].default, Some("staging".to_string()));
+        assert!(rec.vars[2].list);
+
+        let call = |name: &str, args: &[&str]| RecipeCall {
+            name: name.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+        };
+        assert!(vm.run_recipes(&[call("deploy", &[])]).is_ok());
+        assert_eq!(vm.varmgr.var("res"), VarValue::from("staging:out/bin:"));
+        assert!(vm.run_recipes(&[call("deploy", &["--env=prod", "web", "api"])]).is_ok());
+        assert_eq!(vm.varmgr.var("res"), VarValue::from("prod:web:api"));
+        assert!(vm.run_recipes(&[call("deploy", &["web", "--dst=/tmp", "--env=dev"])]).is_ok());
+        assert_eq!(vm.varmgr.var("res"), VarValue::from("dev:/tmp:web"));
+        assert!(vm.run_recipes(&[call("need", &["--b=1", "x"])]).is_ok());
+        assert_eq!(vm.varmgr.var("res"), VarValue::from("x1"));
+        assert!(vm.run_recipes(&[call("need", &[])]).is_err());
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str("bad +a=\"1\":\n").is_err());
+    }
+
+    #[test]
+    fn recipe_dep_args() {
+        let opts = RunOpts::new();
+        let mut vm = Engine::new(opts);
+        let src = "cnt = 0\ngen:\ncnt = inc($cnt)\nbuild ver mode=\"debug\": gen\nres = \"${res}[${ver}:${mode}]\"\n\
+            release ver: build(${ver}, \"rel\") build(\"${ver}-1\") build(${ver}, \"rel\")\nres = \"${res}r${ver}\"";
+        let res = vm.load_from_str(src);
+        assert!(res.is_ok());
+        let rec = vm.recipes().iter().find(|r| r.name == "release").unwrap();
+        assert_eq!(rec.depends.len(), 3);
+        assert_eq!(rec.depends[0].name, "build");
+        assert_eq!(rec.depends[0].to_string(), "build(${ver}, \"rel\")");
+
+        let calls = vec![RecipeCall { name: "release".to_string(), args: vec!["2".to_string()] }];
+        assert!(vm.run_recipes(&calls).is_ok());
+        assert_eq!(vm.varmgr.var("cnt"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("res"), VarValue::from("[2:rel][2-1:debug]r2"));
+
+        // arguments that look the same when joined are still different calls
+        let mut vm = Engine::new(RunOpts::new());
+        let src = "res = \"\"\nbuild a b=\"-\":\nres = \"${res}[${a}|${b}]\"\n\
+            one: build(\"a, b\")\ntwo: build(\"a\", \"b\")";
+        assert!(vm.load_from_str(src).is_ok());
+        let calls = vec![
+            RecipeCall { name: "one".to_string(), args: Vec::new() },
+            RecipeCall { name: "two".to_string(), args: Vec::new() },
+        ];
+        assert!(vm.run_recipes(&calls).is_ok());
+        assert_eq!(vm.varmgr.var("res"), VarValue::from("[a, b|-][a|b]"));
+    }
+
+    #[test]
+    fn user_funcs() {
+        let opts = RunOpts::new();
+        let mut vm = Engine::new(opts);
+        let src = "g = 5\nfn add(a, b):\nreturn inc($a, $b)\nend\n\
+            fn fib(n)\nif $n < 2\nreturn $n\nend\nreturn add(fib(dec($n)), fib(dec($n, 2)))\nend\n\
+            fn local(v)\ng = $v\nend\nfn os()\nreturn \"user\"\nend\nfn deep(n)\nreturn deep($n)\nend\n\
+            x = add(2, $g)\nbuild v:\nlocal(10)\ny = fib(10)\nz = os()\nu = local(1)";
+        let res = vm.load_from_str(src);
+        assert!(res.is_ok());
+        let calls = vec![RecipeCall { name: "build".to_string(), args: vec!["10".to_string()] }];
+        assert!(vm.run_recipes(&calls).is_ok());
+        assert_eq!(vm.varmgr.var("x"), VarValue::Int(7));
+        assert_eq!(vm.varmgr.var("g"), VarValue::Int(5));
+        assert_eq!(vm.varmgr.var("y"), VarValue::Int(55));
+        assert_eq!(vm.varmgr.var("z"), VarValue::from("user"));
+        assert_eq!(vm.varmgr.var("u"), VarValue::Undefined);
+
+        #[cfg(unix)]
+        {
+            let src = "fn fail()\nexit 3\nend\n-ignore:\nx = fail()\nstrict:\nx = fail()";
+            let mut vm = Engine::new(RunOpts::new());
+            assert!(vm.load_from_str(src).is_ok());
+            assert!(vm.run_recipe("ignore").is_ok());
+            assert!(vm.run_recipe("strict").is_err());
+        }
+
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str("fn two(a, b)\nend\nx = two(1, 2, 3)").is_ok());
+        match vm.exec_init() {
+            Err(HakuError::FuncArgCountError(..)) => {}
+            r => panic!("expected argument count error: {:?}", r),
+        }
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str("fn deep(n)\nreturn deep($n)\nend\nx = deep(1)").is_ok());
+        match vm.exec_init() {
+            Err(HakuError::FuncRecursionError(..)) => {}
+            r => panic!("expected recursion error: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn or_conditions() {
+        let mut vm = Engine::new(RunOpts::new());
+        let src = "a = 0\nb = 1\nif $a || $b\nx = 1\nend\n\
+            if $a\nelseif $a || 3 <= 3\ny = 1\nend\n\
+            while $a || $b\nb = 0\nz = 2 >= 1\nend";
+        assert!(vm.load_from_str(src).is_ok());
+        assert!(vm.exec_init().is_ok());
+        assert_eq!(vm.varmgr.var("x"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("y"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("b"), VarValue::Int(0));
+        assert_eq!(vm.varmgr.var("z"), VarValue::Int(1));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exec_results() {
+        let mut vm = Engine::new(RunOpts::new());
+        let src = "r = `echo out; echo err >&2; exit 5`\ncode = exit-code($r)\nout = stdout($r)\nerr = stderr($r)\nok = $r\ns = `echo done`\nscode = exit_code($s)\nnum = exit-code(\"5\")";
+        assert!(vm.load_from_str(src).is_ok());
+        assert!(vm.exec_init().is_ok());
+        assert_eq!(vm.varmgr.var("code"), VarValue::Int(5));
+        assert_eq!(vm.varmgr.var("out"), VarValue::from("out"));
+        assert_eq!(vm.varmgr.var("err"), VarValue::from("err"));
+        assert!(!vm.varmgr.var("ok").is_true());
+        assert_eq!(vm.varmgr.var("scode"), VarValue::Int(0));
+        assert_eq!(vm.varmgr.var("num"), VarValue::Int(0));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn timeouts() {
+        let src = "#[timeout(200ms)]\nslow:\n@sleep 5\nline:\n#[timeout(100ms)]\n@sleep 5\n\
+            fast:\n#[timeout(5s)]\n@sleep 0\nr = `sleep 0`\nexec:\n#[timeout(100ms)]\nr = `sleep 5`";
+        for (name, ok) in [("slow", false), ("line", false), ("fast", true), ("exec", false)] {
+            let mut vm = Engine::new(RunOpts::new());
+            assert!(vm.load_from_str(src).is_ok());
+            let start = Instant::now();
+            match vm.run_recipe(name) {
+                Ok(()) => assert!(ok, "{}", name),
+                Err(HakuError::TimeoutError(_, _, _)) => assert!(!ok, "{}", name),
+                Err(e) => panic!("{}: {:?}", name, e),
+            }
+            assert!(start.elapsed() < Duration::from_secs(3), "{}", name);
+        }
+
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str("#[timeout(forever)]\na:").is_err());
+    }
+
+    #[test]
+    fn retries() {
+        let src = "flaky:\n#[retry(2, backoff=10ms)]\n@exit 3\n#[retry(1)]\nall:\n@exit 4\n@exit 5";
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str(src).is_ok());
+        let flaky = vm.find_recipe("flaky").unwrap();
+        assert_eq!(flaky.attrs.retry, None);
+        let all = vm.find_recipe("all").unwrap();
+        assert_eq!(all.attrs.retry, Some(Retry { count: 1, backoff: Duration::from_secs(0) }));
+        match vm.run_recipe("flaky") {
+            Err(e) => assert_eq!(e.exit_code(), Some(3)),
+            Ok(_) => panic!("recipe must fail"),
+        }
+
+        for src in ["#[retry]\na:", "#[retry(0)]\na:", "#[retry(2, backoff=soon)]\na:", "#[retry(2, 3)]\na:"] {
+            let mut vm = Engine::new(RunOpts::new());
+            assert!(vm.load_from_str(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn retry_until_success() {
+        // the command fails twice and succeeds on the third run
+        let path = env::temp_dir().join(format!("haku-retry-{}", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let cmd = format!("@echo run >> '{0}'; test $(wc -l < '{0}') -ge 3", path);
+        let src = format!("#[retry(2)]\nthird:\n{0}\n#[retry(1)]\nnever:\n{0}", cmd);
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str(&src).is_ok());
+        assert!(vm.run_recipe("third").is_ok());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        fs::remove_file(&path).unwrap();
+        assert!(vm.run_recipe("never").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn try_catch() {
+        let src = "log = \"\"\ntry\nerror \"boom\"\nlog = \"never\"\ncatch msg, code\nlog = \"catch\"\n\
+            finally\nlog = $log .. \",finally\"\nend\nfor i in 1..3\ntry\nx = 1 / ($i - 1)\ncatch\n\
+            continue\nend\nlog = $log .. \",\" .. $x\nend\ntry\nok = 1\ncatch\nok = 0\nend\n\
+            a:\ntry\nerror \"first\"\nfinally\nclean = 1\nend";
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str(src).is_ok());
+        match vm.run_recipe("a") {
+            Err(HakuError::UserError(_)) => {}
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(vm.varmgr.var("log"), VarValue::from("catch,finally,1"));
+        assert!(vm.varmgr.var("msg").to_string().contains("boom"));
+        assert_eq!(vm.varmgr.var("code"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("ok"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("clean"), VarValue::Int(1));
+
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str("a:\nfinally\nend").is_ok());
+        match vm.run_recipe("a") {
+            Err(HakuError::StrayTryPartError(_, _)) => {}
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn try_jumps() {
+        let src = "log = \"\"\nfor i in 1..4\ntry\nif $i == 2\ncontinue\nend\nif $i == 3\nbreak\nend\n\
+            log = $log .. \"b\" .. $i\nfinally\nlog = $log .. \"f\" .. $i\nend\nend\n\
+            fn helper()\nreturn 7\nend\n\
+            fn first(n)\ntry\nif $n > 0\nreturn $n * 10\nend\nfinally\nh = helper()\nend\nreturn $h\nend\n\
+            r = first(2)\nz = first(0)\n\
+            ret:\ntry\ntry\nreturn\nfinally\nout = \"inner\"\nend\nfinally\nout = $out .. \",outer\"\nend\nafter = 1";
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str(src).is_ok());
+        assert!(vm.run_recipe("ret").is_ok());
+        assert_eq!(vm.varmgr.var("log"), VarValue::from("b1f1f2f3"));
+        // a function called from `finally` does not change the value being returned
+        assert_eq!(vm.varmgr.var("r"), VarValue::Int(20));
+        assert_eq!(vm.varmgr.var("z"), VarValue::Int(7));
+        assert_eq!(vm.varmgr.var("out"), VarValue::from("inner,outer"));
+        assert_eq!(vm.varmgr.var("after"), VarValue::Undefined);
+    }
+
+    #[test]
+    fn recipe_hooks() {
+        let src = "#[finally(down), on_failure(notify)]\nbad:\nerror \"boom\"\n#[finally(down)]\ngood:\n\
+            #[on-failure(notify)]\nfine:\ndown:\ncleaned = $error_code\nnotify:\nnotified = $error_recipe\n\
+            #[finally(bad)]\nhook_fails:";
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str(src).is_ok());
+        let bad = vm.find_recipe("bad").unwrap();
+        assert_eq!(bad.attrs.finally, vec!["down".to_string()]);
+        assert_eq!(bad.attrs.on_failure, vec!["notify".to_string()]);
+        match vm.run_recipe("bad") {
+            Err(HakuError::UserError(_)) => {}
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(vm.varmgr.var("cleaned"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("notified"), VarValue::from("bad"));
+
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str(src).is_ok());
+        assert!(vm.run_recipe("good").is_ok());
+        assert_eq!(vm.varmgr.var("cleaned"), VarValue::Int(0));
+        assert!(vm.run_recipe("fine").is_ok());
+        assert_eq!(vm.varmgr.var("notified"), VarValue::Undefined);
+        assert!(vm.run_recipe("hook_fails").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn signal_stops_script() {
+        // the signal is sent to the whole process, so the test runs itself in a child process
+        // to keep other tests away from it
+        if env::var_os("HAKU_TEST_SIGNAL").is_none() {
+            let status = Command::new(env::current_exe().unwrap())
+                .args(["--exact", "vm::vm_test::signal_stops_script", "--test-threads=1"])
+                .env("HAKU_TEST_SIGNAL", "1")
+                .stdout(Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success(), "{:?}", status);
+            return;
+        }
+
+        let marker = env::temp_dir().join(format!("haku-signal-{}", std::process::id()));
+        let src = format!(
+            "#[finally(down), on_failure(notify)]\nslow:\ntry\n@touch '{}' && exec sleep 5\ncatch\n\
+             caught = 1\nfinally\ncleaned = 1\nend\ndown:\nhooked = 1\nnotify:\nnotified = $error_code",
+            marker.to_string_lossy()
+        );
+        let mut vm = Engine::new(RunOpts::new().with_signals(true));
+        assert!(vm.load_from_str(&src).is_ok());
+        let path = marker.clone();
+        let sender = thread::spawn(move || {
+            while !path.exists() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            unsafe {
+                libc::kill(libc::getpid(), libc::SIGTERM);
+            }
+        });
+        let start = Instant::now();
+        match vm.run_recipe("slow") {
+            Err(e @ HakuError::Interrupted(_, _, _)) => {
+                assert_eq!(e.kind(), 36);
+                assert_eq!(e.exit_code(), Some(128 + libc::SIGTERM));
+            }
+            r => panic!("{:?}", r),
+        }
+        sender.join().unwrap();
+        fs::remove_file(&marker).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert_eq!(vm.varmgr.var("caught"), VarValue::Undefined);
+        assert_eq!(vm.varmgr.var("cleaned"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("hooked"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("notified"), VarValue::Int(128 + libc::SIGTERM as i64));
+    }
+
+    #[test]
+    fn env_files() {
+        let path = env::temp_dir().join(format!("haku-env-{}", std::process::id()));
+        fs::write(&path, "export HAKU_T_A=one\nHAKU_T_B=\"${HAKU_T_A}-two\"\n").unwrap();
+        let path = path.to_string_lossy().to_string();
+
+        let mut vm = Engine::new(RunOpts::new().with_env_files(vec![path.clone()]));
+        let src = "a:\n  b = $HAKU_T_B\n  c = `echo $HAKU_T_A`\n";
+        assert!(vm.load_from_str(src).is_ok());
+        assert!(vm.run_recipe("a").is_ok());
+        assert_eq!(vm.varmgr.var("b"), VarValue::from("one-two"));
+        assert_eq!(vm.varmgr.var("c").to_string(), "one");
+
+        let mut vm = Engine::new(RunOpts::new());
+        let src = format!("n = env-file(\"{}\")\nv = $HAKU_T_B\n", path.replace('\\', "/"));
+        assert!(vm.load_from_str(&src).is_ok());
+        assert!(vm.exec_init().is_ok());
+        assert_eq!(vm.varmgr.var("n"), VarValue::Int(2));
+        assert_eq!(vm.varmgr.var("v"), VarValue::from("one-two"));
+
+        let mut vm = Engine::new(RunOpts::new().with_env_files(vec![format!("{}.none", path)]));
+        assert!(vm.load_from_str("a:\n").is_ok());
+        match vm.run_recipe("a") {
+            Err(e) => assert_eq!(e.kind(), 17),
+            Ok(_) => panic!("missing env file must fail"),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cli_vars() {
+        let vars = vec![("rel".to_string(), "1".to_string()), ("mode".to_string(), "cli".to_string())];
+        let mut vm = Engine::new(RunOpts::new().with_vars(vars));
+        let src = "rel ?= 0\ntarget ?= \"x86\"\nmode = \"fixed\"\na:\n  b = \"${rel}-${target}-${mode}\"\n";
+        assert!(vm.load_from_str(src).is_ok());
+        let globals = vm.global_vars().unwrap();
+        let names: Vec<&str> = globals.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["rel", "mode", "target"]);
+        assert!(vm.run_recipe("a").is_ok());
+        assert_eq!(vm.varmgr.var("b"), VarValue::from("1-x86-fixed"));
+    }
+
+    #[test]
+    fn typed_values() {
+        let mut vm = Engine::new(RunOpts::new());
+        let src = r#"l = [1, "a", [2, 3], true]
+m = {gcc: "x86_64", "arm board": {cc: "clang"}, n: 5,}
+a = $l[0] + $m["n"]
+b = $m["arm board"]["cc"] ~ $l[-3]
+c = $l[2][1] == 3 && $l[3] == true && !$l[10]
+d = [1, 2] == [1, "2"]
+keys = ""
+for k in $m
+  keys = $keys ~ $k ~ ";"
+end
+sum = 0
+for x in [1, 2]
+  sum = $sum + 1
+end
+"#;
+        let res = vm.load_from_str(src);
+        assert!(res.is_ok());
+        let res = vm.exec_init();
+        assert!(res.is_ok());
+        assert_eq!(vm.varmgr.var("a"), VarValue::Int(6));
+        assert_eq!(vm.varmgr.var("b"), VarValue::from("clanga"));
+        assert_eq!(vm.varmgr.var("c"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("d"), VarValue::Int(1));
+        assert_eq!(vm.varmgr.var("keys"), VarValue::from("arm board;gcc;n;"));
+        assert_eq!(vm.varmgr.var("sum"), VarValue::Int(2));
+        match vm.varmgr.var("l") {
+            VarValue::List(v) => {
+                assert_eq!(v.len(), 4);
+                assert_eq!(v[3], VarValue::Bool(true));
+            }
+            v => panic!("list expected, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn dep_trees() {
+        let src = "release: build test win\nbuild: clean\ntest: build\nclean:\n#[os(nothing)]\nwin:\na: b\nb: a none\n";
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str(src).is_ok());
+        let trees = vm.dep_tree(&[]);
+        assert_eq!(trees.len(), 2);
+        let rel = &trees[0];
+        assert_eq!(rel.name, "release");
+        let names: Vec<&str> = rel.depends.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["build", "test", "win"]);
+        assert_eq!(rel.depends[0].kind, DepKind::Recipe);
+        assert_eq!(rel.depends[0].depends[0].name, "clean");
+        assert_eq!(rel.depends[1].depends[0].kind, DepKind::Seen);
+        assert_eq!(rel.depends[2].kind, DepKind::Disabled("#[os(nothing)]".to_string()));
+        // recipes of a cycle are not dependencies of other recipes, but they are shown
+        let a = &trees[1];
+        assert_eq!(a.name, "a");
+        assert_eq!(a.depends[0].depends[0].kind, DepKind::Cycle);
+        assert_eq!(a.depends[0].depends[1].kind, DepKind::Missing);
+
+        let trees = vm.dep_tree(&["test".to_string()]);
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees[0].depends[0].kind, DepKind::Recipe);
+    }
+
+    #[test]
+    fn exit_codes() {
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str("a:\n@exit 3\nb:\n@echo ok").is_ok());
+        match vm.run_recipe("a") {
+            Err(e) => {
+                assert_eq!(e.exit_code(), Some(3));
+                assert_eq!(e.kind(), 31);
+            }
+            Ok(_) => panic!("recipe must fail"),
+        }
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.load_from_str("a:\n@exit 3").is_ok());
+        match vm.run_recipe("c") {
+            Err(e) => {
+                assert_eq!(e.exit_code(), None);
+                assert_eq!(e.kind(), 21);
+            }
+            Ok(_) => panic!("recipe must not be found"),
+        }
+        let e = HakuError::Interrupted("a".to_string(), 2, String::new());
+        assert_eq!(e.exit_code(), Some(130));
+        assert_eq!(e.kind(), 36);
+    }
+
+    #[test]
+    fn arith() {
+        let exprs = vec![
+            ("a = 1 + 2 * 3", VarValue::Int(7)),
+            ("a = (1 + 2) * 3", VarValue::Int(9)),
+            ("a = 10 - 4 - 3", VarValue::Int(3)),
+            ("a = -7 / 2", VarValue::Int(-3)),
+            ("a = -(2 + 3) % 3", VarValue::Int(-2)),
+            ("a = 2 *-3", VarValue::Int(-6)),
+            ("a = \"5\" + 0x10", VarValue::Int(21)),
+            ("a = \"abc\" + 1", VarValue::Int(1)),
+            ("a = \"v\" .. 1 + 1 ~ \".\" .. 0", VarValue::from("v2.0")),
+            ("a = inc(2 * 3, 1)", VarValue::Int(7)),
+            ("a = !(0 || 0) && 3 <= 3", VarValue::Int(1)),
+            ("a = 2 >= 3", VarValue::Int(0)),
+        ];
+        for (src, val) in exprs {
+            let mut vm = Engine::new(RunOpts::new());
+            assert!(vm.load_from_str(src).is_ok());
+            assert!(vm.exec_init().is_ok(), "{}", src);
+            assert_eq!(vm.varmgr.var("a"), val, "{}", src);
+        }
+        for src in ["a = 1 / 0", "a = 5 % (2 - 2)", "a = 9223372036854775807 + 1", "a = -(-9223372036854775807 - 1)"] {
+            let mut vm = Engine::new(RunOpts::new());
+            assert!(vm.load_from_str(src).is_ok());
+            match vm.exec_init() {
+                Err(HakuError::DivByZeroError(_)) | Err(HakuError::OverflowError(_, _)) => {}
+                r => panic!("{}: {:?}", src, r),
+            }
+        }
+        let mut vm = Engine::new(RunOpts::new());
+        assert!(vm.exec_op(&Op::Arith("+".to_string(), vec![Op::Int(1)])).is_err());
+        assert!(vm.exec_op(&Op::Arith("^".to_string(), vec![Op::Int(1), Op::Int(2)])).is_err());
+        assert!(vm.exec_op(&Op::Compare("==".to_string(), vec![Op::Int(1)])).is_err());
+    }
+
+    #[test]
+    fn parallel_jobs() {
+        let opts = RunOpts::new().with_jobs(4);
+        let mut vm = Engine::new(opts);
+        let res = vm.load_from_str("a:\n@exit 0\nb:\n@exit 0\nall: a b\n@exit 0\nfail: a b\n@exit 1");
+        assert!(res.is_ok());
+        assert!(vm.run_recipe("all").is_ok());
+        assert!(vm.run_recipe("fail").is_err());
+    }
+
     #[test]
     fn ops() {
         let parses: Vec<Prs> = vec![
             Prs { expr: "run('cmd')", tp: Op::Func(String::new(), Vec::new()) },
             Prs { expr: "run('cmd', `abs`, inner(10,2,3))", tp: Op::Func(String::new(), Vec::new()) },
             Prs { expr: "END", tp: Op::StmtClose },
-            Prs { expr: "Return", tp: Op::Return },
+            Prs { expr: "Return", tp: Op::Return(Vec::new()) },
+            Prs { expr: "return $a && 2", tp: Op::Return(Vec::new()) },
+            Prs { expr: "fn add(a, b):", tp: Op::Function(String::new(), Vec::new()) },
+            Prs { expr: "fn no_args()", tp: Op::Function(String::new(), Vec::new()) },
             Prs { expr: "ELse", tp: Op::Else },
+            Prs { expr: "try", tp: Op::Try },
+            Prs { expr: "Catch err, code", tp: Op::Catch(Vec::new()) },
+            Prs { expr: "catch:", tp: Op::Catch(Vec::new()) },
+            Prs { expr: "catcher x", tp: Op::Shell(0, String::new()) },
+            Prs { expr: "finally", tp: Op::Finally },
+            Prs { expr: "a = 1 + $b * (3 - 2)", tp: Op::Assign(String::new(), Vec::new()) },
+            Prs { expr: "a = -$b .. \"x\" ~ inc(2 * 3)", tp: Op::Assign(String::new(), Vec::new()) },
             Prs { expr: "brEAk", tp: Op::Break },
             Prs { expr: "continuE", tp: Op::Continue },
             Prs { expr: "a = `ls` || `dir` && 12 == 'zcv'", tp: Op::Assign(String::new(), Vec::new()) },